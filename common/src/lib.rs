@@ -0,0 +1,213 @@
+//! Types shared by the protocol and the price oracle: the prices in the format of
+//! `priceoracle.testnet`, their ordering and median, and the wide integers they are compared in.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{Balance, Timestamp};
+use std::cmp::Ordering;
+
+pub use uints::{U256, U384};
+
+#[allow(clippy::manual_div_ceil, clippy::assign_op_pattern)]
+mod uints {
+    uint::construct_uint!(
+        pub struct U256(4);
+    );
+
+    uint::construct_uint!(
+        pub struct U384(6);
+    );
+}
+
+pub type AssetId = String;
+pub type DurationSec = u32;
+
+pub const MAX_VALID_DECIMALS: u8 = 77;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetPrice {
+    pub asset_id: AssetId,
+    pub price: Price,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetOptionalPrice {
+    pub asset_id: AssetId,
+    pub price: Option<Price>,
+}
+
+// 5 NEAR = 5 * 10**24 "wrap.near"
+// 50 DAI = 50 * 10**18 "dai.bridge.near"
+
+// Price NEAR { multiplier: 1000, decimals: 26 }
+// 5 NEAR in USD = 5 * 10**24 * 1000 / 10**(26 - 18) = 50 * 10**18
+// Price DAI { multiplier: 101, decimals: 20 }
+// 50 DAI in USD = 50 * 10**18 * 101 / 10**(20 - 18) = 505 * 10**17
+
+/// Price of the smallest unit of an asset, `multiplier / 10^decimals`.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+#[serde(crate = "near_sdk::serde")]
+pub struct Price {
+    #[serde(with = "u128_dec_format")]
+    pub multiplier: Balance,
+    pub decimals: u8,
+}
+
+impl Price {
+    pub fn is_valid(&self) -> bool {
+        self.decimals <= MAX_VALID_DECIMALS
+    }
+
+    pub fn assert_valid(&self) {
+        assert!(self.is_valid());
+    }
+
+    /// Multiplier expressed with `decimals` decimals, which must not be fewer than the price's.
+    pub fn normalized_multiplier(&self, decimals: u8) -> U384 {
+        U384::from(self.multiplier) * U384::exp10((decimals - self.decimals) as usize)
+    }
+}
+
+impl PartialEq<Self> for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Eq for Price {}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let decimals = std::cmp::max(self.decimals, other.decimals);
+        self.normalized_multiplier(decimals)
+            .cmp(&other.normalized_multiplier(decimals))
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceData {
+    #[serde(with = "u64_dec_format")]
+    pub timestamp: Timestamp,
+    pub recency_duration_sec: DurationSec,
+
+    pub prices: Vec<AssetOptionalPrice>,
+}
+
+/// Median of the given prices. With an even number of prices the lower of the two middle
+/// ones is used.
+pub fn median(mut prices: Vec<Price>) -> Option<Price> {
+    prices.sort();
+    let len = prices.len();
+    (len > 0).then(|| prices[(len - 1) / 2])
+}
+
+pub mod u128_dec_format {
+    use near_sdk::serde::de;
+    use near_sdk::serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(num: &u128, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&num.to_string())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u128, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+pub mod u64_dec_format {
+    use near_sdk::serde::de;
+    use near_sdk::serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(num: &u64, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&num.to_string())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u64, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+pub fn to_nano(ts: u32) -> Timestamp {
+    Timestamp::from(ts) * 10u64.pow(9)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price(multiplier: u128, decimals: u8) -> Price {
+        Price {
+            multiplier,
+            decimals,
+        }
+    }
+
+    #[test]
+    fn test_ord() {
+        assert_eq!(price(15000, 6), price(1500, 5));
+        assert!(price(15000, 6) < price(1501, 5));
+        assert!(price(1, 77) < price(1, 0));
+        assert_eq!(
+            [price(3, 1), price(1, 0), price(20, 2)].iter().max(),
+            Some(&price(1, 0))
+        );
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(vec![]), None);
+        assert_eq!(
+            median(vec![price(16000, 6), price(1400, 5), price(15000, 6)]),
+            Some(price(15000, 6))
+        );
+        assert_eq!(
+            median(vec![price(16, 3), price(15000, 6)]),
+            Some(price(15000, 6))
+        );
+    }
+
+    #[test]
+    fn test_dec_format() {
+        let data = PriceData {
+            timestamp: 1_700_000_000_000_000_000,
+            recency_duration_sec: 90,
+            prices: vec![AssetOptionalPrice {
+                asset_id: "wrap.testnet".to_string(),
+                price: Some(price(15000, 6)),
+            }],
+        };
+        let json = near_sdk::serde_json::to_string(&data).unwrap();
+        assert_eq!(
+            json,
+            r#"{"timestamp":"1700000000000000000","recency_duration_sec":90,"prices":[{"asset_id":"wrap.testnet","price":{"multiplier":"15000","decimals":6}}]}"#
+        );
+        let data: PriceData = near_sdk::serde_json::from_str(&json).unwrap();
+        assert_eq!(data.prices[0].price, Some(price(15000, 6)));
+    }
+}
@@ -0,0 +1,188 @@
+//! gUSDT, the receipt token of the protocol reserve: a NEP-141 token deployed on its own account,
+//! the `gusdt` sub-account of the protocol. The protocol, its owner, mints it to the suppliers of
+//! the reserve and burns it when they redeem. Each gUSDT is worth the `exchange_rate` of the
+//! reserve in GUSD, which rises as the borrow fees accrue.
+
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue};
+
+/// gUSDT starts at one GUSD per token and has its decimals.
+pub const DECIMALS: u8 = 6;
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct ReserveToken {
+    /// The protocol, the only account that mints and burns.
+    owner_id: AccountId,
+    token: FungibleToken,
+}
+
+#[near_bindgen]
+impl ReserveToken {
+    #[init]
+    pub fn new(owner_id: AccountId) -> Self {
+        Self {
+            owner_id,
+            token: FungibleToken::new(b"t".to_vec()),
+        }
+    }
+
+    /// Mints `amount` to `account_id`, which must be registered.
+    pub fn ft_mint(&mut self, account_id: AccountId, amount: U128) {
+        self.assert_owner();
+        assert!(
+            self.token.accounts.contains_key(&account_id),
+            "The account {} is not registered with gUSDT",
+            account_id
+        );
+        self.token.internal_deposit(&account_id, amount.0);
+        FtMint {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: Some("Reserve supply"),
+        }
+        .emit();
+    }
+
+    /// Burns `amount` of the gUSDT of `account_id`.
+    pub fn ft_burn(&mut self, account_id: AccountId, amount: U128) {
+        self.assert_owner();
+        assert!(
+            self.token.accounts.get(&account_id).unwrap_or_default() >= amount.0,
+            "Not enough gUSDT"
+        );
+        self.token.internal_withdraw(&account_id, amount.0);
+        FtBurn {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: Some("Reserve withdrawal"),
+        }
+        .emit();
+    }
+}
+
+impl ReserveToken {
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can mint and burn"
+        );
+    }
+
+    /// Hook of the token for gUSDT that would leave the supply without the protocol redeeming
+    /// it, which would leave its GUSD in the reserve for good. As for GUSD, it panics.
+    fn reject_burn(&self, account_id: AccountId, amount: Balance) {
+        assert!(
+            amount == 0,
+            "{} gUSDT of {} can only be burned by redeeming them",
+            amount,
+            account_id
+        );
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(ReserveToken, token, reject_burn);
+near_contract_standards::impl_fungible_token_storage!(ReserveToken, token, reject_burn);
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for ReserveToken {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "Gratis Reserve USDT".to_string(),
+            symbol: "gUSDT".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: DECIMALS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::{get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_NEAR};
+
+    fn set_context(predecessor: &str, deposit: u128) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(deposit)
+            .build());
+    }
+
+    /// Alice holds 100 gUSDT minted by the protocol.
+    fn setup() -> ReserveToken {
+        set_context("gratis.near", 0);
+        let mut contract = ReserveToken::new("gratis.near".parse().unwrap());
+        set_context("alice.near", ONE_NEAR);
+        contract.storage_deposit(None, None);
+        set_context("gratis.near", 0);
+        contract.ft_mint("alice.near".parse().unwrap(), U128(100));
+        contract
+    }
+
+    #[test]
+    fn test_mint_and_burn() {
+        let mut contract = setup();
+        assert!(get_logs()[0].contains(r#""event":"ft_mint""#));
+        assert_eq!(contract.ft_balance_of("alice.near".parse().unwrap()).0, 100);
+        assert_eq!(contract.ft_metadata().symbol, "gUSDT");
+
+        contract.ft_burn("alice.near".parse().unwrap(), U128(40));
+        assert!(get_logs()[1].contains(r#""event":"ft_burn""#));
+        assert_eq!(contract.ft_balance_of("alice.near".parse().unwrap()).0, 60);
+        assert_eq!(contract.ft_total_supply().0, 60);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut contract = setup();
+        set_context("bob.near", ONE_NEAR);
+        contract.storage_deposit(None, None);
+        set_context("alice.near", 1);
+        contract.ft_transfer("bob.near".parse().unwrap(), U128(30), None);
+        assert_eq!(contract.ft_balance_of("bob.near".parse().unwrap()).0, 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "The account bob.near is not registered with gUSDT")]
+    fn test_mint_to_unregistered_account() {
+        let mut contract = setup();
+        contract.ft_mint("bob.near".parse().unwrap(), U128(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can mint and burn")]
+    fn test_burn_owner_only() {
+        let mut contract = setup();
+        set_context("alice.near", 0);
+        contract.ft_burn("alice.near".parse().unwrap(), U128(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gUSDT")]
+    fn test_burn_more_than_held() {
+        let mut contract = setup();
+        contract.ft_burn("alice.near".parse().unwrap(), U128(101));
+    }
+
+    #[test]
+    #[should_panic(expected = "100 gUSDT of alice.near can only be burned by redeeming them")]
+    fn test_force_unregister_rejected() {
+        let mut contract = setup();
+        set_context("alice.near", 1);
+        contract.storage_unregister(Some(true));
+    }
+}
@@ -0,0 +1,130 @@
+//! Mintable NEP-141 token standing in for `usdt.fakes.testnet` in the sandbox tests and local
+//! demos. The owner mints to any account registered through `storage_deposit`.
+
+use near_contract_standards::fungible_token::events::FtMint;
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault, PromiseOrValue};
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockFt {
+    owner_id: AccountId,
+    token: FungibleToken,
+    metadata: FungibleTokenMetadata,
+}
+
+#[near_bindgen]
+impl MockFt {
+    #[init]
+    pub fn new(owner_id: AccountId, name: String, symbol: String, decimals: u8) -> Self {
+        let metadata = FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name,
+            symbol,
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals,
+        };
+        metadata.assert_valid();
+        let mut token = FungibleToken::new(b"t".to_vec());
+        token.internal_register_account(&owner_id);
+        Self {
+            owner_id,
+            token,
+            metadata,
+        }
+    }
+
+    /// Mints `amount` to `account_id`, which must be registered.
+    pub fn ft_mint(&mut self, account_id: AccountId, amount: U128) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can mint"
+        );
+        assert!(
+            self.token.accounts.contains_key(&account_id),
+            "The account {} is not registered",
+            account_id
+        );
+        self.token.internal_deposit(&account_id, amount.0);
+        FtMint {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: None,
+        }
+        .emit();
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(MockFt, token);
+near_contract_standards::impl_fungible_token_storage!(MockFt, token);
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for MockFt {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        self.metadata.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::{get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, ONE_NEAR};
+
+    fn set_context(predecessor: &str, deposit: u128) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .attached_deposit(deposit)
+            .build());
+    }
+
+    fn setup() -> MockFt {
+        set_context("usdt.near", 0);
+        MockFt::new(
+            "usdt.near".parse().unwrap(),
+            "Tether USD".to_string(),
+            "USDT".to_string(),
+            6,
+        )
+    }
+
+    #[test]
+    fn test_mint_to_registered_account() {
+        let mut contract = setup();
+        set_context("alice.near", ONE_NEAR);
+        contract.storage_deposit(None, None);
+
+        set_context("usdt.near", 0);
+        contract.ft_mint("alice.near".parse().unwrap(), U128(100));
+        assert_eq!(contract.ft_balance_of("alice.near".parse().unwrap()).0, 100);
+        assert_eq!(contract.ft_total_supply().0, 100);
+        assert_eq!(contract.ft_metadata().decimals, 6);
+        assert!(get_logs()[0].contains(r#""event":"ft_mint""#));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not registered")]
+    fn test_mint_to_unregistered_account() {
+        let mut contract = setup();
+        contract.ft_mint("alice.near".parse().unwrap(), U128(100));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can mint")]
+    fn test_mint_owner_only() {
+        let mut contract = setup();
+        set_context("alice.near", 0);
+        contract.ft_mint("alice.near".parse().unwrap(), U128(100));
+    }
+}
@@ -0,0 +1,270 @@
+//! Push-based price oracle. Allow-listed reporters push prices and `get_price_data` serves the
+//! median of the recent reports through the interface of `priceoracle.testnet`, so the protocol
+//! can be pointed at our own feed with `set_oracles`.
+
+use gratis_common::{median, to_nano, AssetId, DurationSec, MAX_VALID_DECIMALS};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U64;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, PanicOnDefault};
+use std::collections::{HashMap, HashSet};
+
+pub use gratis_common::{AssetOptionalPrice, AssetPrice, Price, PriceData};
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Report {
+    pub price: Price,
+    pub timestamp: U64,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Oracle {
+    pub allowed_accounts: HashSet<AccountId>,
+    /// Latest price pushed by each reporter for each asset.
+    pub reports: HashMap<AccountId, HashMap<AssetId, Report>>,
+    /// Reports older than this are ignored.
+    pub recency_duration_sec: DurationSec,
+}
+
+#[near_bindgen]
+impl Oracle {
+    #[init]
+    pub fn new(allowed_accounts: Vec<AccountId>, recency_duration_sec: DurationSec) -> Self {
+        assert!(
+            env::state_read::<Self>().is_none(),
+            "Contract is already initialized"
+        );
+        Self::assert_owner();
+
+        Self {
+            allowed_accounts: allowed_accounts.into_iter().collect(),
+            reports: HashMap::new(),
+            recency_duration_sec,
+        }
+    }
+
+    pub fn add_reporter(&mut self, account_id: AccountId) {
+        Self::assert_owner();
+        self.allowed_accounts.insert(account_id);
+    }
+
+    pub fn remove_reporter(&mut self, account_id: AccountId) {
+        Self::assert_owner();
+        self.allowed_accounts.remove(&account_id);
+        self.reports.remove(&account_id);
+    }
+
+    pub fn get_reporters(&self) -> Vec<AccountId> {
+        self.allowed_accounts.iter().cloned().collect()
+    }
+
+    pub fn report_prices(&mut self, prices: Vec<AssetPrice>) {
+        let account_id = env::predecessor_account_id();
+        assert!(
+            self.allowed_accounts.contains(&account_id),
+            "Only allowed accounts can report prices"
+        );
+        self.insert_reports(account_id, prices, env::block_timestamp());
+    }
+
+    /// Reports prices as the owner, as if they were pushed at `timestamp`, to replay old or
+    /// stale prices in tests and demos. The timestamp can not be in the future.
+    pub fn report_prices_at(&mut self, prices: Vec<AssetPrice>, timestamp: U64) {
+        Self::assert_owner();
+        assert!(
+            timestamp.0 <= env::block_timestamp(),
+            "Timestamp is in the future"
+        );
+        self.insert_reports(env::current_account_id(), prices, timestamp.0);
+    }
+
+    pub fn get_reports(&self, asset_id: AssetId) -> HashMap<AccountId, Report> {
+        self.reports
+            .iter()
+            .filter_map(|(account_id, reports)| {
+                reports
+                    .get(&asset_id)
+                    .map(|report| (account_id.clone(), *report))
+            })
+            .collect()
+    }
+
+    /// Median of the recent reports for every requested asset, all reported assets if none
+    /// are given.
+    pub fn get_price_data(&self, asset_ids: Option<Vec<AssetId>>) -> PriceData {
+        let asset_ids = asset_ids.unwrap_or_else(|| {
+            let asset_ids: HashSet<AssetId> = self
+                .reports
+                .values()
+                .flat_map(|reports| reports.keys().cloned())
+                .collect();
+            asset_ids.into_iter().collect()
+        });
+
+        let timestamp = env::block_timestamp();
+        let min_timestamp = timestamp.saturating_sub(to_nano(self.recency_duration_sec));
+        PriceData {
+            timestamp,
+            recency_duration_sec: self.recency_duration_sec,
+            prices: asset_ids
+                .into_iter()
+                .map(|asset_id| {
+                    let prices = self
+                        .reports
+                        .values()
+                        .filter_map(|reports| reports.get(&asset_id))
+                        .filter(|report| report.timestamp.0 >= min_timestamp)
+                        .map(|report| report.price)
+                        .collect();
+                    AssetOptionalPrice {
+                        price: median(prices),
+                        asset_id,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Oracle {
+    fn insert_reports(&mut self, account_id: AccountId, prices: Vec<AssetPrice>, timestamp: u64) {
+        let timestamp = U64(timestamp);
+        let reports = self.reports.entry(account_id).or_default();
+        for AssetPrice { asset_id, price } in prices {
+            assert!(price.multiplier > 0, "Price should be greater than 0");
+            assert!(
+                price.decimals <= MAX_VALID_DECIMALS,
+                "Invalid price decimals"
+            );
+            reports.insert(asset_id, Report { price, timestamp });
+        }
+    }
+
+    fn assert_owner() {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Only contract owner can call this method"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use near_sdk::{test_utils::VMContextBuilder, testing_env};
+
+    fn set_context(predecessor: &str, timestamp_sec: u32) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(predecessor.parse().unwrap())
+            .block_timestamp(to_nano(timestamp_sec))
+            .build());
+    }
+
+    fn near_price(multiplier: u128, decimals: u8) -> Vec<AssetPrice> {
+        vec![AssetPrice {
+            asset_id: "wrap.testnet".to_string(),
+            price: Price {
+                multiplier,
+                decimals,
+            },
+        }]
+    }
+
+    fn setup() -> Oracle {
+        set_context("alice.near", 100);
+        Oracle::new(
+            vec![
+                "bob.near".parse().unwrap(),
+                "carol.near".parse().unwrap(),
+                "dave.near".parse().unwrap(),
+            ],
+            60,
+        )
+    }
+
+    #[test]
+    fn test_median_price() {
+        let mut oracle = setup();
+        set_context("bob.near", 100);
+        oracle.report_prices(near_price(15000, 6));
+        set_context("carol.near", 100);
+        oracle.report_prices(near_price(1400, 5));
+        set_context("dave.near", 100);
+        oracle.report_prices(near_price(16000, 6));
+
+        let data = oracle.get_price_data(Some(vec![
+            "wrap.testnet".to_string(),
+            "usdt.fakes.testnet".to_string(),
+        ]));
+        assert_eq!(data.recency_duration_sec, 60);
+        assert_eq!(data.prices[0].asset_id, "wrap.testnet");
+        assert_eq!(data.prices[0].price.unwrap().multiplier, 15000);
+        assert!(data.prices[1].price.is_none());
+    }
+
+    #[test]
+    fn test_stale_reports_ignored() {
+        let mut oracle = setup();
+        set_context("bob.near", 100);
+        oracle.report_prices(near_price(15000, 6));
+        set_context("carol.near", 150);
+        oracle.report_prices(near_price(16000, 6));
+
+        set_context("alice.near", 170);
+        let data = oracle.get_price_data(None);
+        assert_eq!(data.prices[0].price.unwrap().multiplier, 16000);
+
+        set_context("alice.near", 220);
+        let data = oracle.get_price_data(None);
+        assert!(data.prices[0].price.is_none());
+    }
+
+    #[test]
+    fn test_owner_reports_at_timestamp() {
+        let mut oracle = setup();
+        set_context("bob.near", 150);
+        oracle.report_prices(near_price(15000, 6));
+        set_context("alice.near", 150);
+        oracle.report_prices_at(near_price(16000, 6), U64(to_nano(80)));
+
+        // The owner report is already older than the recency duration.
+        let data = oracle.get_price_data(None);
+        assert_eq!(data.prices[0].price.unwrap().multiplier, 15000);
+        let reports = oracle.get_reports("wrap.testnet".to_string());
+        assert_eq!(
+            reports[&"alice.near".parse::<AccountId>().unwrap()].timestamp,
+            U64(to_nano(80))
+        );
+
+        oracle.report_prices_at(near_price(14000, 6), U64(to_nano(140)));
+        let data = oracle.get_price_data(None);
+        assert_eq!(data.prices[0].price.unwrap().multiplier, 14000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only contract owner can call this method")]
+    fn test_report_at_owner_only() {
+        let mut oracle = setup();
+        set_context("bob.near", 100);
+        oracle.report_prices_at(near_price(15000, 6), U64(to_nano(100)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Timestamp is in the future")]
+    fn test_report_at_future_timestamp() {
+        let mut oracle = setup();
+        oracle.report_prices_at(near_price(15000, 6), U64(to_nano(101)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only allowed accounts can report prices")]
+    fn test_report_not_allowed() {
+        let mut oracle = setup();
+        set_context("eve.near", 100);
+        oracle.report_prices(near_price(15000, 6));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::signed_decimal::SignedDecimal;
 use crate::*;
 use near_sdk::borsh::maybestd::io::Write;
 use near_sdk::json_types::U128;
@@ -5,28 +6,55 @@ use near_sdk::serde::Serializer;
 use std::cmp::Ordering;
 use std::fmt::{Display, Formatter};
 use std::ops::{Add, Div, Mul, Sub};
-#[cfg(not(target_arch = "wasm32"))]
 use std::str::FromStr;
 
-uint::construct_uint!(
-    pub struct U256(4);
-);
-
-uint::construct_uint!(
-    pub struct U384(6);
-);
-
 pub(crate) const MAX_RATIO: u32 = 10000;
 
 const NUM_DECIMALS: u8 = 27;
 const BIG_DIVISOR: u128 = 10u128.pow(NUM_DECIMALS as u32);
-const HALF_DIVISOR: u128 = BIG_DIVISOR / 2;
+/// ln(2) with 27 decimals, rounded down.
+const LN_2: u128 = 693_147_180_559_945_309_417_232_121;
 
 pub type LowU128 = U128;
 
 #[derive(Copy, Clone)]
 pub struct BigDecimal(U384);
 
+/// How results that do not fit the precision are rounded. Debt owed to the protocol should be
+/// rounded up, collateral and payouts owed by it down.
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
+pub enum Rounding {
+    Floor,
+    Ceil,
+    HalfUp,
+    /// Half to the nearest even result, the banker's rounding.
+    HalfEven,
+}
+
+/// `num / den` rounded with the given mode.
+fn div_rounded(num: U384, den: U384, rounding: Rounding) -> U384 {
+    let (quotient, remainder) = num.div_mod(den);
+    if remainder.is_zero() {
+        return quotient;
+    }
+    // remainder < den, so this is the comparison of 2 * remainder with den without overflow.
+    let half = (den - remainder).cmp(&remainder);
+    let round_up = match rounding {
+        Rounding::Floor => false,
+        Rounding::Ceil => true,
+        Rounding::HalfUp => half != Ordering::Greater,
+        Rounding::HalfEven => {
+            half == Ordering::Less || (half == Ordering::Equal && quotient.bit(0))
+        }
+    };
+    if round_up {
+        quotient + 1
+    } else {
+        quotient
+    }
+}
+
 impl Default for BigDecimal {
     fn default() -> Self {
         BigDecimal::zero()
@@ -52,32 +80,35 @@ impl std::fmt::Debug for BigDecimal {
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-const PARSE_INT_ERROR: &'static str = "Parse int error";
+const PARSE_INT_ERROR: &str = "Parse int error";
 
-#[cfg(not(target_arch = "wasm32"))]
 impl FromStr for BigDecimal {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let dot_pos = s.find('.');
-        let (int, dec) = (s, 0u128);
-
-        if let Some(dot_pos) = dot_pos {
-            (
-                &s[..dot_pos],
-                format!("{:0<27}", &s[dot_pos + 1..])
-                    .parse()
-                    .map_err(|_| PARSE_INT_ERROR)?,
-            )
-        } else {
-            (s, 0u128)
+        let (int, dec) = match s.find('.') {
+            Some(dot_pos) => (&s[..dot_pos], &s[dot_pos + 1..]),
+            None => (s, ""),
         };
-        let int = U384::from_str(&int).map_err(|_| PARSE_INT_ERROR)?;
-        if dec >= BIG_DIVISOR {
-            return Err(String::from("The decimal part is too large"));
+        if int.is_empty() || !int.chars().chain(dec.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(String::from(PARSE_INT_ERROR));
+        }
+        if dec.len() > NUM_DECIMALS as usize {
+            return Err(format!("At most {} decimals are supported", NUM_DECIMALS));
         }
-        Ok(Self(int * U384::from(BIG_DIVISOR) + U384::from(dec)))
+
+        let int = U384::from_dec_str(int).map_err(|_| PARSE_INT_ERROR)?;
+        let dec = if dec.is_empty() {
+            0
+        } else {
+            format!("{:0<27}", dec)
+                .parse::<u128>()
+                .map_err(|_| PARSE_INT_ERROR)?
+        };
+        int.checked_mul(U384::from(BIG_DIVISOR))
+            .and_then(|int| int.checked_add(U384::from(dec)))
+            .map(Self)
+            .ok_or_else(|| String::from("The number is too large"))
     }
 }
 
@@ -90,7 +121,6 @@ impl Serialize for BigDecimal {
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
 impl<'de> Deserialize<'de> for BigDecimal {
     fn deserialize<D>(
         deserializer: D,
@@ -99,7 +129,31 @@ impl<'de> Deserialize<'de> for BigDecimal {
         D: near_sdk::serde::Deserializer<'de>,
     {
         let s: String = Deserialize::deserialize(deserializer)?;
-        Ok(Self::from_str(&s).map_err(|err| near_sdk::serde::de::Error::custom(err))?)
+        Self::from_str(&s).map_err(near_sdk::serde::de::Error::custom)
+    }
+}
+
+/// Serializes ratios in basis points of `MAX_RATIO` as decimal strings, 50 being "0.005".
+pub mod ratio_dec_format {
+    use super::BigDecimal;
+    use near_sdk::serde::de;
+    use near_sdk::serde::{Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S>(ratio: &u32, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&BigDecimal::from_ratio(*ratio).to_string())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u32, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        BigDecimal::from_str(&String::deserialize(deserializer)?)
+            .and_then(|value| value.try_to_ratio())
+            .map_err(de::Error::custom)
     }
 }
 
@@ -151,7 +205,7 @@ impl Mul for BigDecimal {
     type Output = Self;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        Self((self.0 * rhs.0 + U384::from(HALF_DIVISOR)) / U384::from(BIG_DIVISOR))
+        self.mul_with(rhs, Rounding::HalfUp)
     }
 }
 
@@ -159,7 +213,7 @@ impl Div for BigDecimal {
     type Output = Self;
 
     fn div(self, rhs: Self) -> Self::Output {
-        Self((self.0 * U384::from(BIG_DIVISOR) + U384::from(HALF_DIVISOR)) / rhs.0)
+        self.div_with(rhs, Rounding::HalfUp)
     }
 }
 
@@ -180,6 +234,19 @@ impl BigDecimal {
         Self(U384::from(ratio) * U384::from(BIG_DIVISOR / (MAX_RATIO as u128)))
     }
 
+    /// Ratio in basis points of `MAX_RATIO`, failing if the value is not a whole number of
+    /// basis points.
+    pub fn try_to_ratio(&self) -> Result<u32, String> {
+        let (ratio, remainder) = self.0.div_mod(U384::from(BIG_DIVISOR / MAX_RATIO as u128));
+        if !remainder.is_zero() {
+            return Err(format!("{} is not a multiple of 1/{}", self, MAX_RATIO));
+        }
+        if ratio > U384::from(u32::MAX) {
+            return Err(format!("{} is too large for a ratio", self));
+        }
+        Ok(ratio.as_u32())
+    }
+
     pub fn mul_ratio(&self, ratio: u32) -> Self {
         Self((self.0 * U384::from(ratio) + U384::from(MAX_RATIO / 2)) / U384::from(MAX_RATIO))
     }
@@ -199,7 +266,74 @@ impl BigDecimal {
     }
 
     pub fn round_u128(&self) -> u128 {
-        ((self.0 + U384::from(HALF_DIVISOR)) / U384::from(BIG_DIVISOR)).as_u128()
+        self.round_u128_with(Rounding::HalfUp)
+    }
+
+    pub fn floor_u128(&self) -> u128 {
+        self.round_u128_with(Rounding::Floor)
+    }
+
+    pub fn ceil_u128(&self) -> u128 {
+        self.round_u128_with(Rounding::Ceil)
+    }
+
+    pub fn round_u128_with(&self, rounding: Rounding) -> u128 {
+        self.try_round_u128_with(rounding)
+            .expect("Value does not fit in u128")
+    }
+
+    /// Rounds to the nearest integer, `None` if it does not fit in a `u128`.
+    pub fn try_round_u128(&self) -> Option<u128> {
+        self.try_round_u128_with(Rounding::HalfUp)
+    }
+
+    pub fn try_round_u128_with(&self, rounding: Rounding) -> Option<u128> {
+        let value = div_rounded(self.0, U384::from(BIG_DIVISOR), rounding);
+        (value <= U384::from(u128::MAX)).then(|| value.as_u128())
+    }
+
+    pub fn checked_add(&self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Self)
+    }
+
+    pub fn checked_sub(&self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Self)
+    }
+
+    pub fn checked_mul(&self, rhs: Self) -> Option<Self> {
+        self.checked_mul_with(rhs, Rounding::HalfUp)
+    }
+
+    pub fn checked_mul_with(&self, rhs: Self, rounding: Rounding) -> Option<Self> {
+        let value = self.0.checked_mul(rhs.0)?;
+        Some(Self(div_rounded(value, U384::from(BIG_DIVISOR), rounding)))
+    }
+
+    /// `None` on overflow or division by zero.
+    pub fn checked_div(&self, rhs: Self) -> Option<Self> {
+        self.checked_div_with(rhs, Rounding::HalfUp)
+    }
+
+    pub fn checked_div_with(&self, rhs: Self, rounding: Rounding) -> Option<Self> {
+        if rhs.0.is_zero() {
+            return None;
+        }
+        let value = self.0.checked_mul(U384::from(BIG_DIVISOR))?;
+        Some(Self(div_rounded(value, rhs.0, rounding)))
+    }
+
+    pub fn mul_with(&self, rhs: Self, rounding: Rounding) -> Self {
+        self.checked_mul_with(rhs, rounding)
+            .expect("Multiplication overflow")
+    }
+
+    pub fn div_with(&self, rhs: Self, rounding: Rounding) -> Self {
+        self.checked_div_with(rhs, rounding)
+            .expect("Division overflow or division by zero")
+    }
+
+    pub fn saturating_sub(&self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
     }
 
     #[cfg(not(target_arch = "wasm32"))]
@@ -210,7 +344,11 @@ impl BigDecimal {
     }
 
     pub fn round_mul_u128(&self, rhs: u128) -> u128 {
-        ((self.0 * U384::from(rhs) + U384::from(HALF_DIVISOR)) / U384::from(BIG_DIVISOR)).as_u128()
+        self.round_mul_u128_with(rhs, Rounding::HalfUp)
+    }
+
+    pub fn round_mul_u128_with(&self, rhs: u128, rounding: Rounding) -> u128 {
+        div_rounded(self.0 * U384::from(rhs), U384::from(BIG_DIVISOR), rounding).as_u128()
     }
 
     pub fn div_u128(&self, rhs: u128) -> BigDecimal {
@@ -241,6 +379,98 @@ impl BigDecimal {
 
         res
     }
+
+    /// Square root, rounded down. Exact to the last of the 27 decimals.
+    pub fn sqrt(&self) -> Self {
+        let scaled = self
+            .0
+            .checked_mul(U384::from(BIG_DIVISOR))
+            .expect("Square root overflow");
+        Self(scaled.integer_sqrt())
+    }
+
+    /// `e^self`. The relative error is below `1e-24`. Panics if the result exceeds `2^290`,
+    /// roughly `self > 200`.
+    pub fn exp(&self) -> Self {
+        // self = k * ln(2) + r with 0 <= r < ln(2), so e^self = 2^k * e^r.
+        let (k, r) = self.0.div_mod(U384::from(LN_2));
+        assert!(k <= U384::from(290), "Exponent overflow");
+        let r = Self(r);
+
+        let mut sum = Self::one();
+        let mut term = Self::one();
+        let mut i = 1u128;
+        while !term.0.is_zero() {
+            term = (term * r).div_u128(i);
+            sum = sum + term;
+            i += 1;
+        }
+        Self(sum.0 << k.as_usize())
+    }
+
+    /// Natural logarithm. The absolute error is below `1e-25`. Panics for zero.
+    pub fn ln(&self) -> SignedDecimal {
+        assert!(!self.0.is_zero(), "Logarithm of zero");
+        let one = U384::from(BIG_DIVISOR);
+
+        // self = m * 2^k (or 2^-k) with 1 <= m < 2.
+        let (m, k, negative) = if self.0 >= one {
+            let mut k = self.0.bits() - one.bits();
+            if self.0 >> k < one {
+                k -= 1;
+            }
+            (Self(self.0 >> k), k, false)
+        } else {
+            let mut k = one.bits() - self.0.bits();
+            if self.0 << k < one {
+                k += 1;
+            }
+            (Self(self.0 << k), k, true)
+        };
+
+        // ln(m) = 2 * atanh(z) = 2 * (z + z^3 / 3 + z^5 / 5 + ...) with z = (m - 1) / (m + 1).
+        let z = (m - Self::one()) / (m + Self::one());
+        let z2 = z * z;
+        let mut power = z;
+        let mut series = Self::zero();
+        let mut i = 1u128;
+        while !power.0.is_zero() {
+            series = series + power.div_u128(i);
+            power = power * z2;
+            i += 2;
+        }
+        let ln_m = SignedDecimal::from(series + series);
+        let k_ln_2 = SignedDecimal::from(Self(U384::from(LN_2) * U384::from(k)));
+        if negative {
+            ln_m - k_ln_2
+        } else {
+            ln_m + k_ln_2
+        }
+    }
+
+    /// `self^exponent` for fractional exponents, computed as `e^(exponent * ln(self))`. The
+    /// relative error is about `exponent * 1e-25` on top of the one of `exp`; prefer `pow` for
+    /// integer exponents. Results below the precision underflow to zero.
+    pub fn pow_fraction(&self, exponent: BigDecimal) -> Self {
+        if self.0.is_zero() {
+            return if exponent.0.is_zero() {
+                Self::one()
+            } else {
+                Self::zero()
+            };
+        }
+        let y = self.ln() * SignedDecimal::from(exponent);
+        if y.is_negative() {
+            // e^-63 is below half of the smallest unit, larger negative exponents underflow to
+            // zero before `exp` would overflow.
+            if y.abs() > Self::from(63u32) {
+                return Self::zero();
+            }
+            Self::one() / y.abs().exp()
+        } else {
+            y.abs().exp()
+        }
+    }
 }
 
 impl PartialEq<Self> for BigDecimal {
@@ -272,7 +502,7 @@ mod tests {
     use super::*;
 
     // Number of milliseconds in a regular year.
-    const N: u64 = 31540000000;
+    const N: u64 = 31536000000;
     // X = 2
     const LOW_X: LowU128 = U128(2000000000000000000000000000);
     // R ** N = X. So R = X ** (1/N)
@@ -287,10 +517,9 @@ mod tests {
         let ap = (a + p / 2) / p;
         let bp = (b + p / 2) / p;
         assert_eq!(
-            ap,
-            bp,
-            "{}",
-            format!("Expected {} to eq {}, with precision {}", a, b, prec)
+            ap, bp,
+            "Expected {} to eq {}, with precision {}",
+            a, b, prec
         );
     }
 
@@ -310,65 +539,231 @@ mod tests {
         assert_eq!((b(3) / b(5)).round_u128(), 1);
     }
 
-    // #[test]
-    // fn test_pow() {
-    //     let r = BigDecimal::from(LOW_R);
-    //     let x = r.pow(N);
-    //     let low_x = LowU128::from(x);
-    //     almost_eq(LOW_X.0, low_x.0, 15);
-    // }
-
-    // #[test]
-    // fn test_compound_pow() {
-    //     fn test(split_n: u64) {
-    //         let r = BigDecimal::from(LOW_R);
-    //         let initial_val = 12345 * 10u128.pow(24);
-    //         let mut val = initial_val;
-    //         for i in 1..=split_n {
-    //             let exponent = (N * i / split_n) - (N * (i - 1) / split_n);
-    //             let interest = r.pow(exponent);
-    //             val = interest.round_mul_u128(val);
-    //         }
-    //         almost_eq(val, initial_val * 2, 15);
-    //     }
-
-    //     (1..=100).for_each(test);
-    // }
-
-    // #[test]
-    // fn test_compound_pow_precision() {
-    //     fn test(split_n: u64) {
-    //         let r = BigDecimal::from(LOW_R);
-    //         let initial_val = 12345 * 10u128.pow(24);
-    //         let mut val = initial_val;
-    //         let exponent = N / split_n;
-    //         assert_eq!(exponent * split_n, N);
-    //         let interest = r.pow(exponent);
-    //         for _ in 1..=split_n {
-    //             val = interest.round_mul_u128(val);
-    //         }
-    //         almost_eq(val, initial_val * 2, 15);
-    //     }
-    //     test(N / 60000);
-    //     test(N / 1000000);
-    //     test(N / (24 * 60 * 60));
-    // }
-
-    // #[test]
-    // fn test_compound_pow_random() {
-    //     const MAX_STEP: u64 = 1000000;
-    //     let r = BigDecimal::from(LOW_R);
-    //     let initial_val = 12345 * 10u128.pow(24);
-    //     let mut val = initial_val;
-    //     let mut total_exponent = 0;
-    //     while total_exponent < N {
-    //         let exponent = std::cmp::min(N - total_exponent, rng.next_u64() % MAX_STEP + 1);
-    //         total_exponent += exponent;
-    //         let interest = r.pow(exponent);
-    //         val = interest.round_mul_u128(val);
-    //     }
-    //     almost_eq(val, initial_val * 2, 15);
-    // }
+    #[test]
+    fn test_pow() {
+        let r = BigDecimal::from(LOW_R);
+        let x = r.pow(N);
+        let low_x = LowU128::from(x);
+        almost_eq(LOW_X.0, low_x.0, 15);
+    }
+
+    #[test]
+    fn test_compound_pow() {
+        fn test(split_n: u64) {
+            let r = BigDecimal::from(LOW_R);
+            let initial_val = 12345 * 10u128.pow(24);
+            let mut val = initial_val;
+            for i in 1..=split_n {
+                let exponent = (N * i / split_n) - (N * (i - 1) / split_n);
+                let interest = r.pow(exponent);
+                val = interest.round_mul_u128(val);
+            }
+            almost_eq(val, initial_val * 2, 15);
+        }
+
+        (1..=100).for_each(test);
+    }
+
+    #[test]
+    fn test_compound_pow_precision() {
+        fn test(split_n: u64) {
+            let r = BigDecimal::from(LOW_R);
+            let initial_val = 12345 * 10u128.pow(24);
+            let mut val = initial_val;
+            let exponent = N / split_n;
+            assert_eq!(exponent * split_n, N);
+            let interest = r.pow(exponent);
+            for _ in 1..=split_n {
+                val = interest.round_mul_u128(val);
+            }
+            almost_eq(val, initial_val * 2, 15);
+        }
+        test(N / 60000);
+        test(N / 1000000);
+        test(N / (24 * 60 * 60));
+    }
+
+    #[test]
+    fn test_compound_pow_random() {
+        const MAX_STEP: u64 = 1000000;
+        // xorshift64, deterministic so failures can be reproduced.
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let mut next_u64 = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let r = BigDecimal::from(LOW_R);
+        let initial_val = 12345 * 10u128.pow(24);
+        let mut val = initial_val;
+        let mut total_exponent = 0;
+        while total_exponent < N {
+            let exponent = std::cmp::min(N - total_exponent, next_u64() % MAX_STEP + 1);
+            total_exponent += exponent;
+            let interest = r.pow(exponent);
+            val = interest.round_mul_u128(val);
+        }
+        almost_eq(val, initial_val * 2, 15);
+    }
+
+    fn raw(x: BigDecimal) -> u128 {
+        LowU128::from(x).0
+    }
+
+    #[test]
+    fn test_sqrt() {
+        assert_eq!(b(16).sqrt(), b(4));
+        assert_eq!(b(0).sqrt(), b(0));
+        assert_eq!(raw(b(2).sqrt()), 1_414213562_373095048_801688724);
+        assert_eq!(
+            BigDecimal::from_str("0.25").unwrap().sqrt(),
+            BigDecimal::from_str("0.5").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_exp() {
+        assert_eq!(b(0).exp(), b(1));
+        almost_eq(raw(b(1).exp()), 2_718281828_459045235_360287471, 24);
+        // e^10 = 22026.465794806716516957900645284
+        almost_eq(
+            raw(b(10).exp().div_u128(10000)),
+            2_202646579_480671651_695790064,
+            22,
+        );
+        almost_eq(
+            raw(BigDecimal::from_str("0.5").unwrap().exp()),
+            1_648721270_700128146_848650787,
+            24,
+        );
+    }
+
+    #[test]
+    fn test_ln() {
+        assert_eq!(b(1).ln(), SignedDecimal::zero());
+        almost_eq(raw(b(2).ln().abs()), LN_2, 24);
+        almost_eq(raw(b(10).ln().abs()), 2_302585092_994045684_017991454, 24);
+        let ln_half = BigDecimal::from_str("0.5").unwrap().ln();
+        assert!(ln_half.is_negative());
+        almost_eq(raw(ln_half.abs()), LN_2, 24);
+        let ln_small = BigDecimal::from_str("0.000001").unwrap().ln();
+        assert!(ln_small.is_negative());
+        almost_eq(raw(ln_small.abs()), 13_815510557_964274104_107948728, 24);
+        for x in [3u128, 7, 1000, 123456789] {
+            almost_eq(raw(b(x).ln().abs().exp() / b(x)), BIG_DIVISOR, 20);
+        }
+    }
+
+    #[test]
+    fn test_pow_fraction() {
+        let half = BigDecimal::from_str("0.5").unwrap();
+        almost_eq(raw(b(2).pow_fraction(half)), raw(b(2).sqrt()), 24);
+        almost_eq(raw(b(4).pow_fraction(b(3))), raw(b(64)), 22);
+        almost_eq(raw(half.pow_fraction(b(2))), raw(half * half), 24);
+        assert_eq!(b(0).pow_fraction(half), b(0));
+        assert_eq!(b(0).pow_fraction(b(0)), b(1));
+        assert_eq!(half.pow_fraction(b(1000)), b(0));
+        // 0.5^80 is still about 8e-25, 0.5^1000 underflows.
+        almost_eq(raw(half.pow_fraction(b(80))), raw(half.pow(80)), 26);
+
+        // The interest rate compounds to 2 over a year, also with a fractional exponent.
+        let r = BigDecimal::from(LOW_R);
+        almost_eq(raw(r.pow_fraction(BigDecimal::from(N))), LOW_X.0, 15);
+        let half_year = r.pow_fraction(BigDecimal::from(N) / b(2));
+        almost_eq(raw(half_year), raw(b(2).sqrt()), 15);
+    }
+
+    #[test]
+    fn test_checked_ops() {
+        let max = BigDecimal(U384::MAX);
+        assert_eq!(b(5).checked_add(b(2)), Some(b(7)));
+        assert_eq!(max.checked_add(b(1)), None);
+        assert_eq!(b(5).checked_sub(b(2)), Some(b(3)));
+        assert_eq!(b(2).checked_sub(b(5)), None);
+        assert_eq!(b(2).saturating_sub(b(5)), b(0));
+        assert_eq!(b(5).checked_mul(b(2)), Some(b(10)));
+        assert_eq!(max.checked_mul(b(2)), None);
+        assert_eq!(b(10).checked_div(b(4)).unwrap().to_string(), "2.5");
+        assert_eq!(b(1).checked_div(b(0)), None);
+        assert_eq!(max.checked_div(b(1)), None);
+    }
+
+    #[test]
+    fn test_try_round_u128() {
+        assert_eq!(b(u128::MAX).try_round_u128(), Some(u128::MAX));
+        assert_eq!((b(u128::MAX) + b(1)).try_round_u128(), None);
+        assert_eq!(BigDecimal(U384::MAX).try_round_u128(), None);
+        assert_eq!(BigDecimal::from(2.5f64).try_round_u128(), Some(3));
+    }
+
+    #[test]
+    fn test_rounding_modes() {
+        let x = |s: &str| BigDecimal::from_str(s).unwrap();
+        let round = |v: BigDecimal| {
+            [
+                Rounding::Floor,
+                Rounding::Ceil,
+                Rounding::HalfUp,
+                Rounding::HalfEven,
+            ]
+            .map(|rounding| v.round_u128_with(rounding))
+        };
+        assert_eq!(round(x("2")), [2, 2, 2, 2]);
+        assert_eq!(round(b(2) + b(1) / b(10)), [2, 3, 2, 2]);
+        assert_eq!(round(b(5) / b(2)), [2, 3, 3, 2]);
+        assert_eq!(round(b(7) / b(2)), [3, 4, 4, 4]);
+        assert_eq!(round(b(27) / b(10)), [2, 3, 3, 3]);
+
+        let third = b(1).div_with(b(3), Rounding::Floor);
+        assert_eq!(third.round_mul_u128_with(3, Rounding::Ceil), 1);
+        assert_eq!(third.round_mul_u128_with(3, Rounding::Floor), 0);
+        assert!(b(1).div_with(b(3), Rounding::Ceil) > third);
+        assert_eq!(
+            third.mul_with(b(3), Rounding::Floor),
+            b(1).saturating_sub(BigDecimal(U384::one()))
+        );
+        assert_eq!((b(18) / b(5)).round_u128(), 4);
+    }
+
+    #[test]
+    fn test_from_str() {
+        let x = |s: &str| BigDecimal::from_str(s);
+        assert_eq!(x("10"), Ok(b(10)));
+        assert_eq!(x("1.5"), Ok(b(3) / b(2)));
+        assert_eq!(x("0.005"), Ok(BigDecimal::from_ratio(50)));
+        assert_eq!(x("2.").unwrap(), b(2));
+        assert_eq!(
+            x("0.000000000000000000000000001"),
+            Ok(BigDecimal(U384::one()))
+        );
+        assert!(x("0.0000000000000000000000000001").is_err());
+        assert!(x("").is_err());
+        assert!(x(".5").is_err());
+        assert!(x("1.-5").is_err());
+        assert!(x("0x10").is_err());
+        assert!(x(&"9".repeat(100)).is_err());
+
+        for v in ["0.0", "1.0", "123.456", "0.000000000000000000000000001"] {
+            assert_eq!(x(v).unwrap().to_string(), v);
+        }
+        let v: BigDecimal = near_sdk::serde_json::from_str("\"1.25\"").unwrap();
+        assert_eq!(v, b(5) / b(4));
+    }
+
+    #[test]
+    fn test_try_to_ratio() {
+        assert_eq!(
+            BigDecimal::from_str("0.005").unwrap().try_to_ratio(),
+            Ok(50)
+        );
+        assert_eq!(b(1).try_to_ratio(), Ok(MAX_RATIO));
+        assert!(BigDecimal::from_str("0.00005")
+            .unwrap()
+            .try_to_ratio()
+            .is_err());
+        assert!(b(1_000_000).try_to_ratio().is_err());
+    }
 
     #[test]
     fn test_display() {
@@ -379,3 +774,88 @@ mod tests {
         assert!(BigDecimal::from(0.5f64).to_string().starts_with("0.500000"));
     }
 }
+
+#[cfg(test)]
+mod proptests {
+    use super::*;
+
+    use near_sdk::borsh::BorshSerialize;
+    use num_bigint::BigUint;
+    use proptest::prelude::*;
+
+    /// Any value with an integer part that fits in a `u128`.
+    fn big_decimal() -> impl Strategy<Value = BigDecimal> {
+        (any::<u128>(), 0..BIG_DIVISOR)
+            .prop_map(|(int, dec)| BigDecimal(U384::from(int) * U384::from(BIG_DIVISOR) + dec))
+    }
+
+    /// Values up to a million, the range loan math works in relative to prices and ratios.
+    fn small_decimal() -> impl Strategy<Value = BigDecimal> {
+        (0..1_000_000u128, 0..BIG_DIVISOR)
+            .prop_map(|(int, dec)| BigDecimal(U384::from(int) * U384::from(BIG_DIVISOR) + dec))
+    }
+
+    fn ulps(a: BigDecimal, b: BigDecimal) -> U384 {
+        if a.0 > b.0 {
+            a.0 - b.0
+        } else {
+            b.0 - a.0
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn display_from_str_roundtrip(x in big_decimal()) {
+            prop_assert_eq!(BigDecimal::from_str(&x.to_string()), Ok(x));
+        }
+
+        #[test]
+        fn borsh_roundtrip(x in big_decimal()) {
+            let bytes = x.try_to_vec().unwrap();
+            prop_assert_eq!(BigDecimal::try_from_slice(&bytes).unwrap(), x);
+        }
+
+        #[test]
+        fn mul_associativity_bounds(
+            x in small_decimal(),
+            y in small_decimal(),
+            z in small_decimal(),
+        ) {
+            // Each product rounds by at most half a unit, which the outer factor scales.
+            let bound = U384::from(x.ceil_u128() + z.ceil_u128() + 1);
+            prop_assert!(ulps((x * y) * z, x * (y * z)) <= bound);
+        }
+
+        #[test]
+        fn div_mul_bounds(x in small_decimal(), y in small_decimal()) {
+            prop_assume!(y > BigDecimal::one().div_u128(1_000_000));
+            let bound = U384::from(y.ceil_u128() + 1);
+            prop_assert!(ulps((x / y) * y, x) <= bound);
+            prop_assert!(x.div_with(y, Rounding::Floor) <= x / y);
+            prop_assert!(x / y <= x.div_with(y, Rounding::Ceil));
+            prop_assert!(
+                ulps(x.div_with(y, Rounding::Floor), x.div_with(y, Rounding::Ceil)) <= U384::one()
+            );
+        }
+
+        #[test]
+        fn from_balance_price_matches_reference(
+            balance in any::<u128>(),
+            multiplier in any::<u128>(),
+            decimals in 0..=MAX_VALID_DECIMALS / 2,
+            extra_decimals in 0..=MAX_VALID_DECIMALS / 2,
+        ) {
+            let price = Price { multiplier, decimals };
+            let value = BigDecimal::from_balance_price(balance, &price, extra_decimals);
+
+            let num = BigUint::from(balance) * BigUint::from(multiplier);
+            let denominator_decimals = (decimals + extra_decimals) as u32;
+            let reference = if denominator_decimals > NUM_DECIMALS as u32 {
+                num / BigUint::from(10u8).pow(denominator_decimals - NUM_DECIMALS as u32)
+            } else {
+                num * BigUint::from(10u8).pow(NUM_DECIMALS as u32 - denominator_decimals)
+            };
+            prop_assert_eq!(value.0.to_string(), reference.to_string());
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::*;
+
+use near_sdk::Timestamp;
+
+/// Holds back price updates that move too far at once. A held back update pauses liquidations
+/// for the configured cooldown and only takes effect once a later update confirms it. Borrows and
+/// collateral withdrawals wait for that as well.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CircuitBreaker {
+    /// Latest update that moved the price beyond `Config::max_price_change`.
+    pub pending_price_data: Option<PriceData>,
+    #[serde(with = "u64_dec_format")]
+    pub liquidations_paused_until: Timestamp,
+}
+
+/// Returns true if `price` moved away from `previous` by more than `max_change`, in basis
+/// points. The multipliers are compared exactly, at the larger of the two decimals.
+pub(crate) fn moved_beyond(previous: &Price, price: &Price, max_change: u32) -> bool {
+    let decimals = std::cmp::max(previous.decimals, price.decimals);
+    let previous = previous.normalized_multiplier(decimals);
+    let value = price.normalized_multiplier(decimals);
+    let diff = if value > previous {
+        value - previous
+    } else {
+        previous - value
+    };
+    diff * U384::from(MAX_RATIO) > previous * U384::from(max_change)
+}
+
+/// Returns true if any asset priced in both `previous` and `data` moved by more than
+/// `max_change`, in basis points.
+fn exceeds_change(previous: &PriceData, data: &PriceData, max_change: u32) -> bool {
+    data.prices
+        .iter()
+        .any(|AssetOptionalPrice { asset_id, price }| {
+            let previous = previous
+                .prices
+                .iter()
+                .find(|p| &p.asset_id == asset_id)
+                .and_then(|p| p.price);
+            match (previous, price) {
+                (Some(previous), Some(price)) => moved_beyond(&previous, price, max_change),
+                _ => false,
+            }
+        })
+}
+
+impl LendingProtocol {
+    /// Stores an update unless it moves the price beyond the configured bound. Such an update
+    /// is kept as pending and pauses liquidations, later jumps replace it without extending the
+    /// pause. Once the cooldown is over, an update that stays within the bound of the pending one
+    /// confirms the move and is stored.
+    ///
+    /// Returns true if the update was stored.
+    pub(crate) fn apply_price_update(&mut self, data: PriceData) -> bool {
+        let max_change = self.config.max_price_change;
+        let jumped = self
+            .price_data
+            .as_ref()
+            .is_some_and(|previous| exceeds_change(previous, &data, max_change));
+        let confirmed = self
+            .circuit_breaker
+            .pending_price_data
+            .as_ref()
+            .is_some_and(|pending| {
+                !self.are_liquidations_paused() && !exceeds_change(pending, &data, max_change)
+            });
+
+        if jumped && !confirmed {
+            if self.circuit_breaker.pending_price_data.is_none() {
+                self.circuit_breaker.liquidations_paused_until =
+                    env::block_timestamp() + to_nano(self.config.price_cooldown_sec);
+            }
+            log!(
+                r#"EVENT_JSON:{{"standard":"gratis","version":"1.0.0","event":"price_circuit_breaker","data":[{{"paused_until":"{}","price_data":{}}}]}}"#,
+                self.circuit_breaker.liquidations_paused_until,
+                near_sdk::serde_json::to_string(&data).unwrap()
+            );
+            self.circuit_breaker.pending_price_data = Some(data);
+            return false;
+        }
+
+        self.circuit_breaker.pending_price_data = None;
+        self.record_price_data(&data);
+        self.price_data = Some(data);
+        true
+    }
+
+    pub(crate) fn are_liquidations_paused(&self) -> bool {
+        env::block_timestamp() < self.circuit_breaker.liquidations_paused_until
+    }
+
+    /// Borrows and withdrawals are valued at the stored price, which a pending update may be
+    /// about to replace.
+    pub(crate) fn assert_no_pending_price(&self) {
+        assert!(
+            self.circuit_breaker.pending_price_data.is_none(),
+            "A price update is pending, borrows and withdrawals are paused"
+        );
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    pub fn get_circuit_breaker(&self) -> CircuitBreaker {
+        self.circuit_breaker.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_price_change() {
+        let previous = Price {
+            multiplier: 15000,
+            decimals: 6,
+        };
+        let price = Price {
+            multiplier: 1200,
+            decimals: 5,
+        };
+        // A 20% drop, or a 25% rise the other way around.
+        assert!(!moved_beyond(&previous, &price, 2000));
+        assert!(moved_beyond(&previous, &price, 1999));
+        assert!(!moved_beyond(&price, &previous, 2500));
+        assert!(moved_beyond(&price, &previous, 2499));
+    }
+
+    #[test]
+    fn test_price_change_beyond_big_decimal_precision() {
+        // Both prices round to zero with 27 decimals, the move is still 10%.
+        let previous = Price {
+            multiplier: 15000,
+            decimals: 40,
+        };
+        let price = Price {
+            multiplier: 16500,
+            decimals: 40,
+        };
+        assert!(!moved_beyond(&previous, &price, 1000));
+        assert!(moved_beyond(&previous, &price, 999));
+    }
+
+    #[test]
+    fn test_jump_is_held_back() {
+        set_context_at("alice.near", 0, 100);
+        let mut contract = LendingProtocol::new(vec![]);
+
+        assert!(contract.apply_price_update(near_price_data(16000)));
+        assert!(!contract.apply_price_update(near_price_data(8000)));
+        assert_eq!(contract.near_price().multiplier, 16000);
+        assert!(contract.are_liquidations_paused());
+
+        // A recovering feed simply replaces the pending update.
+        assert!(contract.apply_price_update(near_price_data(15500)));
+        assert!(contract.get_circuit_breaker().pending_price_data.is_none());
+        assert_eq!(contract.near_price().multiplier, 15500);
+    }
+
+    #[test]
+    fn test_jump_confirmed_after_cooldown() {
+        set_context_at("alice.near", 0, 100);
+        let mut contract = LendingProtocol::new(vec![]);
+
+        assert!(!contract.apply_price_update(near_price_data(8000)));
+        set_context_at("alice.near", 0, 200);
+        assert!(!contract.apply_price_update(near_price_data(8100)));
+        // Later jumps do not extend the pause of the first one.
+        assert_eq!(
+            contract.get_circuit_breaker().liquidations_paused_until,
+            to_nano(100 + contract.config.price_cooldown_sec)
+        );
+
+        set_context_at("alice.near", 0, 100 + contract.config.price_cooldown_sec);
+        assert!(contract.apply_price_update(near_price_data(8200)));
+        assert_eq!(contract.near_price().multiplier, 8200);
+        assert!(!contract.are_liquidations_paused());
+    }
+
+    #[test]
+    #[should_panic(expected = "Liquidations are paused")]
+    fn test_liquidations_paused() {
+        set_context_at("alice.near", 0, 100);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.apply_price_update(near_price_data(5000));
+        contract.liquidate("bob.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "A price update is pending, borrows and withdrawals are paused")]
+    fn test_borrow_paused_while_pending() {
+        set_context_at("alice.near", 0, 100);
+        let mut contract = LendingProtocol::new(vec![]);
+        set_context_at("alice.near", 100000, 100);
+        contract.deposit_collateral();
+
+        contract.apply_price_update(near_price_data(30000));
+        set_context_at("alice.near", 0, 100);
+        contract.borrow(100);
+    }
+}
@@ -0,0 +1,581 @@
+use crate::*;
+
+use near_sdk::is_promise_success;
+
+/// How collateral is priced: at the spot price for views, at the lower of spot and TWAP for
+/// borrowing and withdrawals, and at the TWAP for liquidations.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Valuation {
+    Spot,
+    Conservative,
+    Twap,
+}
+
+/// Prices of the collateral assets taken the same way.
+pub(crate) struct CollateralPrices {
+    near: Price,
+    tokens: HashMap<AssetId, Price>,
+}
+
+impl CollateralPrices {
+    pub fn near(&self) -> &Price {
+        &self.near
+    }
+
+    pub fn token(&self, asset_id: &AssetId) -> &Price {
+        self.tokens
+            .get(asset_id)
+            .unwrap_or_else(|| panic!("No price for {}", asset_id))
+    }
+
+    /// Exact value of an amount of collateral token.
+    fn token_value(&self, asset_id: &AssetId, amount: Balance) -> BigDecimal {
+        BigDecimal::from_balance_price(amount, self.token(asset_id), 0)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollateralContribution {
+    pub asset_id: AssetId,
+    pub amount: U128,
+    pub value: U128,
+    /// Share of the value that can be borrowed against.
+    #[serde(with = "ratio_dec_format")]
+    pub collateral_factor: u32,
+    /// Share of the value the debt may reach before the loan is liquidatable.
+    #[serde(with = "ratio_dec_format")]
+    pub liquidation_threshold: u32,
+    pub borrowing_power: U128,
+}
+
+impl LendingProtocol {
+    /// Collateral assets deposited as NEP-141 tokens, every collateral asset but NEAR.
+    pub(crate) fn collateral_tokens(&self) -> &[AssetId] {
+        &self.config.collateral_assets[1..]
+    }
+
+    fn spot_price(&self, asset_id: &AssetId) -> Option<Price> {
+        self.get_latest_price()
+            .prices
+            .into_iter()
+            .find(|p| &p.asset_id == asset_id)
+            .and_then(|p| p.price)
+    }
+
+    fn token_price(&self, asset_id: &AssetId, valuation: Valuation) -> Option<Price> {
+        let spot = self.spot_price(asset_id)?;
+        let twap = || self.get_twap(asset_id.clone()).unwrap_or(spot);
+        Some(match valuation {
+            Valuation::Spot => spot,
+            Valuation::Conservative => std::cmp::min(spot, twap()),
+            Valuation::Twap => twap(),
+        })
+    }
+
+    pub(crate) fn collateral_prices(&self, valuation: Valuation) -> CollateralPrices {
+        CollateralPrices {
+            near: match valuation {
+                Valuation::Spot => self.near_price(),
+                Valuation::Conservative => self.conservative_near_price(),
+                Valuation::Twap => self.near_twap(),
+            },
+            tokens: self
+                .collateral_tokens()
+                .iter()
+                .filter_map(|asset_id| {
+                    self.token_price(asset_id, valuation)
+                        .map(|price| (asset_id.clone(), price))
+                })
+                .collect(),
+        }
+    }
+
+    fn collateral_config(&self, asset_id: &AssetId) -> &CollateralConfig {
+        self.config
+            .collateral_configs
+            .get(asset_id)
+            .unwrap_or_else(|| panic!("{} is not accepted as collateral", asset_id))
+    }
+
+    /// Value of NEAR and token collateral, rounded down.
+    pub(crate) fn collateral_totals_value(
+        &self,
+        prices: &CollateralPrices,
+        near: Balance,
+        tokens: &HashMap<AssetId, Balance>,
+    ) -> u128 {
+        tokens
+            .iter()
+            .fold(
+                BigDecimal::from_balance_price(near, prices.near(), 0),
+                |value, (asset_id, amount)| value + prices.token_value(asset_id, *amount),
+            )
+            .floor_u128()
+    }
+
+    /// Value of all collateral of the loan, rounded down.
+    pub(crate) fn loan_collateral_value(&self, loan: &Loan, prices: &CollateralPrices) -> u128 {
+        self.collateral_totals_value(prices, loan.collateral, &loan.token_collateral)
+    }
+
+    /// NEAR collateral of the loan weighted by its collateral ratio, rounded down.
+    fn near_borrowing_power(loan: &Loan, price: &Price) -> u128 {
+        (U256::from(loan.collateral_value(price)) * U256::from(100)
+            / U256::from(loan.collateral_ratio))
+        .as_u128()
+    }
+
+    /// NEAR collateral of the loan weighted by the NEAR liquidation threshold, or by its
+    /// collateral ratio if that is higher. Rounded down.
+    fn near_liquidation_limit(&self, loan: &Loan, price: &Price) -> u128 {
+        let limit = (U256::from(loan.collateral_value(price))
+            * U256::from(self.config.near_liquidation_threshold)
+            / U256::from(MAX_RATIO))
+        .as_u128();
+        std::cmp::max(limit, Self::near_borrowing_power(loan, price))
+    }
+
+    /// `near` plus the sum over the collateral tokens of their value weighted by `factor`.
+    fn weighted_collateral_value(
+        &self,
+        loan: &Loan,
+        prices: &CollateralPrices,
+        near: u128,
+        factor: impl Fn(&CollateralConfig) -> u32,
+    ) -> u128 {
+        let tokens = loan
+            .token_collateral
+            .iter()
+            .fold(BigDecimal::zero(), |value, (asset_id, amount)| {
+                let factor = BigDecimal::from_ratio(factor(self.collateral_config(asset_id)));
+                value
+                    + prices
+                        .token_value(asset_id, *amount)
+                        .mul_with(factor, Rounding::Floor)
+            })
+            .floor_u128();
+        near.checked_add(tokens).expect("Collateral value overflow")
+    }
+
+    /// Debt the loan may take on: its collateral weighted by the collateral factors, NEAR by the
+    /// collateral ratio of the loan.
+    pub(crate) fn borrowing_power(&self, loan: &Loan, prices: &CollateralPrices) -> u128 {
+        let near = Self::near_borrowing_power(loan, prices.near());
+        self.weighted_collateral_value(loan, prices, near, |config| config.collateral_factor)
+    }
+
+    /// Debt the loan may reach before it is liquidatable: its collateral weighted by the
+    /// liquidation thresholds.
+    pub(crate) fn liquidation_limit(&self, loan: &Loan, prices: &CollateralPrices) -> u128 {
+        let near = self.near_liquidation_limit(loan, prices.near());
+        self.weighted_collateral_value(loan, prices, near, |config| config.liquidation_threshold)
+    }
+
+    /// Loan of the account, opened with the collateral ratio of the account if there is none.
+    pub(crate) fn loan_entry(&mut self, account_id: &AccountId) -> &mut Loan {
+        self.apply_pending_redistribution(account_id);
+        let collateral_ratio = if self.lower_collateral_accounts.contains(account_id) {
+            LOWER_COLLATERAL_RATIO
+        } else {
+            MIN_COLLATERAL_RATIO
+        };
+        let redistribution_snapshot = self.redistribution;
+        self.loans.entry(account_id.clone()).or_insert(Loan {
+            collateral: 0,
+            borrowed: 0,
+            collateral_ratio,
+            redistribution_snapshot,
+            token_collateral: HashMap::new(),
+        })
+    }
+
+    /// Adds collateral tokens transferred through `ft_transfer_call` to the loan of `account_id`.
+    /// Token collateral is not charged the deposit fee.
+    pub(crate) fn deposit_token_collateral(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        amount: Balance,
+    ) {
+        assert!(amount > 0, "Deposit Amount should be greater than 0");
+        self.assert_collateral_combination(account_id, asset_id);
+        self.add_token_collateral(account_id, asset_id, amount);
+    }
+
+    /// Adds token collateral to the loan, creating it if needed, and to the totals. Unlike a
+    /// deposit it is not checked against the isolation rules, so that collateral the loan held
+    /// before can always be put back.
+    pub(crate) fn add_token_collateral(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        amount: Balance,
+    ) {
+        let isolated_debt = self.isolated_debt_of(account_id);
+        *self
+            .loan_entry(account_id)
+            .token_collateral
+            .entry(asset_id.clone())
+            .or_default() += amount;
+        *self
+            .total_token_collateral
+            .entry(asset_id.clone())
+            .or_default() += amount;
+        self.update_isolated_debt(account_id, isolated_debt);
+    }
+
+    /// Takes token collateral out of the loan and the totals, dropping emptied entries. Callers
+    /// update the isolated debt.
+    pub(crate) fn take_token_collateral(
+        &mut self,
+        account_id: &AccountId,
+        asset_id: &AssetId,
+        amount: Balance,
+    ) {
+        let take = |balances: &mut HashMap<AssetId, Balance>| {
+            let balance = balances.get_mut(asset_id).expect("No such collateral");
+            *balance = balance
+                .checked_sub(amount)
+                .expect("Withdraw Amount should be less than the deposited amount");
+            if *balance == 0 {
+                balances.remove(asset_id);
+            }
+        };
+        take(
+            &mut self
+                .loans
+                .get_mut(account_id)
+                .expect("No collateral deposited")
+                .token_collateral,
+        );
+        take(&mut self.total_token_collateral);
+    }
+
+    /// Sends collateral out of the protocol, NEAR as a transfer and tokens with `ft_transfer`.
+    /// Receivers of tokens must be registered with the token.
+    pub(crate) fn send_collateral(
+        &self,
+        receiver_id: AccountId,
+        asset_id: &AssetId,
+        amount: Balance,
+    ) -> Promise {
+        if asset_id == &self.config.collateral_assets[0] {
+            return Promise::new(receiver_id).transfer(amount);
+        }
+        ext_usdt::ext(AccountId::from_str(asset_id).unwrap())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(receiver_id, U128(amount), Some("Collateral".to_string()))
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    /// Withdraws token collateral as long as the loan stays within its borrowing power.
+    pub fn remove_token_collateral(&mut self, asset_id: AssetId, amount: U128) -> Promise {
+        assert!(amount.0 > 0, "Withdraw Amount should be greater than 0");
+        self.assert_no_pending_price();
+        let account_id = env::predecessor_account_id();
+        let prices = self.collateral_prices(Valuation::Conservative);
+        self.apply_pending_redistribution(&account_id);
+
+        let mut total_token_collateral = self.total_token_collateral.clone();
+        let total = total_token_collateral.entry(asset_id.clone()).or_default();
+        *total = total
+            .checked_sub(amount.0)
+            .expect("Withdraw Amount should be less than the deposited amount");
+        self.assert_system_change(
+            &prices,
+            self.total_collateral,
+            &total_token_collateral,
+            self.total_debt,
+        );
+        let isolated_debt = self.isolated_debt_of(&account_id);
+        self.take_token_collateral(&account_id, &asset_id, amount.0);
+        self.update_isolated_debt(&account_id, isolated_debt);
+        let loan = &self.loans[&account_id];
+        assert!(
+            self.borrowing_power(loan, &prices) >= loan.borrowed,
+            "Not enough collateral left for the debt"
+        );
+
+        self.send_collateral(account_id.clone(), &asset_id, amount.0)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .resolve_token_collateral_withdraw(account_id, asset_id, amount),
+            )
+    }
+
+    /// Puts token collateral taken from the loan of `account_id` back on it if sending it out,
+    /// to the account or to a liquidator, failed.
+    #[private]
+    pub fn resolve_token_collateral_withdraw(
+        &mut self,
+        account_id: AccountId,
+        asset_id: AssetId,
+        amount: U128,
+    ) {
+        if !is_promise_success() {
+            log!(
+                "Sending {} {} taken from the loan of {} failed, restoring collateral",
+                amount.0,
+                asset_id,
+                account_id
+            );
+            self.add_token_collateral(&account_id, &asset_id, amount.0);
+        }
+    }
+
+    /// Contribution of every collateral asset of the loan at spot prices, NEAR first.
+    pub fn get_collateral_breakdown(&self, account_id: AccountId) -> Vec<CollateralContribution> {
+        let mut loan = match self.get_all_loans().remove(&account_id) {
+            Some(loan) => loan,
+            None => return vec![],
+        };
+        let prices = self.collateral_prices(Valuation::Spot);
+        let near_value = loan.collateral_value(prices.near());
+        let near_factor = (MAX_RATIO as u128 * 100 / loan.collateral_ratio) as u32;
+        let near_power = Self::near_borrowing_power(&loan, prices.near());
+        let mut breakdown = vec![CollateralContribution {
+            asset_id: self.config.collateral_assets[0].clone(),
+            amount: U128(loan.collateral),
+            value: U128(near_value),
+            collateral_factor: near_factor,
+            liquidation_threshold: std::cmp::max(
+                near_factor,
+                self.config.near_liquidation_threshold,
+            ),
+            borrowing_power: U128(near_power),
+        }];
+
+        let mut tokens: Vec<_> = std::mem::take(&mut loan.token_collateral)
+            .into_iter()
+            .collect();
+        tokens.sort();
+        for (asset_id, amount) in tokens {
+            let config = self.collateral_config(&asset_id);
+            let value = prices.token_value(&asset_id, amount);
+            breakdown.push(CollateralContribution {
+                amount: U128(amount),
+                value: U128(value.floor_u128()),
+                collateral_factor: config.collateral_factor,
+                liquidation_threshold: config.liquidation_threshold,
+                borrowing_power: U128(
+                    value
+                        .mul_with(
+                            BigDecimal::from_ratio(config.collateral_factor),
+                            Rounding::Floor,
+                        )
+                        .floor_u128(),
+                ),
+                asset_id,
+            });
+        }
+        breakdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    const WBTC: &str = "wbtc.testnet";
+
+    /// Protocol accepting WBTC at a 70% collateral factor and 80% liquidation threshold. One
+    /// unit of WBTC is worth 2 USDT units.
+    fn setup_with_token() -> LendingProtocol {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        contract.config.collateral_assets.push(WBTC.to_string());
+        contract.config.collateral_configs.insert(
+            WBTC.to_string(),
+            CollateralConfig {
+                collateral_factor: 7000,
+                liquidation_threshold: 8000,
+                debt_ceiling: None,
+            },
+        );
+        let mut data = default_price_data();
+        data.prices.push(AssetOptionalPrice {
+            asset_id: WBTC.to_string(),
+            price: Some(Price {
+                multiplier: 2,
+                decimals: 0,
+            }),
+        });
+        contract.price_data = Some(data);
+        register(&mut contract, "bob.near");
+        register(&mut contract, "carol.near");
+        contract
+    }
+
+    fn deposit_token(contract: &mut LendingProtocol, account: &str, amount: Balance) {
+        set_context(WBTC, 0);
+        contract.ft_on_transfer(account.parse().unwrap(), U128(amount), "".to_string());
+    }
+
+    #[test]
+    fn test_borrowing_power_sums_weighted_assets() {
+        let mut contract = setup_with_token();
+        // NEAR worth 150 at a 120% ratio and a 90% liquidation threshold, WBTC worth 200 at a
+        // 70% factor and an 80% threshold.
+        set_context("bob.near", 10000);
+        contract.deposit_collateral();
+        deposit_token(&mut contract, "bob.near", 100);
+
+        let loan = contract.loans[&"bob.near".parse().unwrap()].clone();
+        let prices = contract.collateral_prices(Valuation::Spot);
+        assert_eq!(contract.loan_collateral_value(&loan, &prices), 350);
+        assert_eq!(contract.borrowing_power(&loan, &prices), 125 + 140);
+        assert_eq!(contract.liquidation_limit(&loan, &prices), 135 + 160);
+
+        // A healthy loan keeps the protocol out of recovery mode.
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        set_context("bob.near", 0);
+        contract.borrow(265);
+        assert_eq!(contract.loans[&"bob.near".parse().unwrap()].borrowed, 265);
+        contract.borrow(1);
+        assert_eq!(contract.loans[&"bob.near".parse().unwrap()].borrowed, 265);
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let mut contract = setup_with_token();
+        set_context("bob.near", 10000);
+        contract.deposit_collateral();
+        deposit_token(&mut contract, "bob.near", 100);
+
+        let breakdown = contract.get_collateral_breakdown("bob.near".parse().unwrap());
+        assert_eq!(breakdown.len(), 2);
+        assert_eq!(breakdown[0].asset_id, "wrap.testnet");
+        assert_eq!(breakdown[0].value.0, 150);
+        assert_eq!(breakdown[0].borrowing_power.0, 125);
+        assert_eq!(breakdown[0].liquidation_threshold, 9000);
+        assert_eq!(breakdown[1].asset_id, WBTC);
+        assert_eq!(breakdown[1].value.0, 200);
+        assert_eq!(breakdown[1].collateral_factor, 7000);
+        assert_eq!(breakdown[1].borrowing_power.0, 140);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough collateral left for the debt")]
+    fn test_remove_token_collateral_keeps_debt_covered() {
+        let mut contract = setup_with_token();
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        deposit_token(&mut contract, "bob.near", 100);
+        set_context("bob.near", 0);
+        contract.borrow(100);
+
+        // 80 WBTC back 112 of borrowing power, 70 only 98.
+        contract.remove_token_collateral(WBTC.to_string(), U128(20));
+        assert_eq!(contract.total_token_collateral[WBTC], 80);
+        contract.remove_token_collateral(WBTC.to_string(), U128(10));
+    }
+
+    #[test]
+    fn test_liquidator_picks_collateral() {
+        let mut contract = setup_with_token();
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        set_context("carol.near", 0);
+        contract.borrow(150);
+        deposit_token(&mut contract, "bob.near", 100);
+        set_context("bob.near", 0);
+        contract.borrow(140);
+
+        // WBTC halves, the 80% threshold of its value no longer covers the debt.
+        contract.price_data.as_mut().unwrap().prices[1].price = Some(Price {
+            multiplier: 1,
+            decimals: 0,
+        });
+        let liquidate = |contract: &mut LendingProtocol, amount: u128| {
+            set_context("carol.near", 0);
+            contract
+                .liquidate_with_repay("bob.near".parse().unwrap(), WBTC.to_string(), U128(amount))
+                .0
+        };
+
+        // 50 repaid for 52 WBTC, the 5% bonus rounded down.
+        assert_eq!(liquidate(&mut contract, 50), 50);
+        // The WBTC go back to the loan if the liquidator can not receive them.
+        let calls: Vec<_> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall { function_name, .. } => Some(function_name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(calls, ["ft_transfer", "resolve_token_collateral_withdraw"]);
+        let bob = &contract.loans[&"bob.near".parse().unwrap()];
+        assert_eq!(bob.borrowed, 90);
+        assert_eq!(bob.token_collateral[WBTC], 48);
+
+        // The remaining 48 WBTC only cover 46 of the debt, only that much is burned.
+        assert_eq!(liquidate(&mut contract, 100), 46);
+        let bob = &contract.loans[&"bob.near".parse().unwrap()];
+        assert_eq!(bob.borrowed, 44);
+        assert!(bob.token_collateral.is_empty());
+        assert!(!contract.total_token_collateral.contains_key(WBTC));
+        assert_eq!(
+            contract.stablecoin_balance(&"carol.near".parse().unwrap()),
+            54
+        );
+        assert_eq!(contract.total_debt, 44 + 150);
+        assert_eq!(contract.stablecoin.total_supply, contract.total_debt);
+    }
+
+    #[test]
+    fn test_close_loan_restores_unsent_tokens() {
+        let mut contract = setup_with_token();
+        let bob: AccountId = "bob.near".parse().unwrap();
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        deposit_token(&mut contract, "bob.near", 100);
+        set_context("bob.near", 0);
+        contract.close_loan();
+        let calls: Vec<_> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall { function_name, .. } => Some(function_name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(calls, ["ft_transfer", "resolve_token_collateral_withdraw"]);
+        assert!(!contract.loans.contains_key(&bob));
+        assert!(!contract.total_token_collateral.contains_key(WBTC));
+
+        // Bob is not registered with WBTC, the transfer fails.
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id("alice.near".parse().unwrap())
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.resolve_token_collateral_withdraw(bob.clone(), WBTC.to_string(), U128(100));
+        assert_eq!(contract.loans[&bob].token_collateral[WBTC], 100);
+        assert_eq!(contract.total_token_collateral[WBTC], 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only collateral tokens are accepted")]
+    fn test_unknown_token_rejected() {
+        let mut contract = setup_with_token();
+        set_context("dai.testnet", 0);
+        contract.ft_on_transfer("bob.near".parse().unwrap(), U128(100), "".to_string());
+    }
+}
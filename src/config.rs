@@ -0,0 +1,232 @@
+use crate::*;
+
+/// Protocol parameters the owner can change after deployment. Ratios are in basis points of
+/// `MAX_RATIO` and passed in JSON as decimal strings, "0.005" being 50.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    /// Fee taken from every collateral deposit.
+    #[serde(with = "ratio_dec_format")]
+    pub deposit_fee: u32,
+    /// Fee added to the debt of every borrow, minted to the reserve suppliers.
+    #[serde(with = "ratio_dec_format")]
+    pub borrow_fee: u32,
+    /// Share of the deposit fee that goes to the insurance fund.
+    #[serde(with = "ratio_dec_format")]
+    pub insurance_deposit_fee_share: u32,
+    /// Share of the liquidation bonus, the collateral seized above the value of the debt, that
+    /// goes to the insurance fund.
+    #[serde(with = "ratio_dec_format")]
+    pub insurance_liquidation_share: u32,
+    /// Largest spread between oracle prices, relative to their median, that is accepted.
+    #[serde(with = "ratio_dec_format")]
+    pub max_price_deviation: u32,
+    /// Window of the time-weighted average price, in seconds.
+    pub twap_window_sec: DurationSec,
+    /// Largest price move accepted in a single update before the circuit breaker trips.
+    #[serde(with = "ratio_dec_format")]
+    pub max_price_change: u32,
+    /// How long liquidations are paused after the circuit breaker trips, in seconds.
+    pub price_cooldown_sec: DurationSec,
+    /// Oracle prices older than this are ignored, in seconds.
+    pub max_price_age_sec: DurationSec,
+    /// Share of the value of the NEAR collateral the debt may reach before the loan can be
+    /// liquidated. Loans whose collateral ratio lets them borrow more against NEAR are
+    /// liquidated past that ratio instead.
+    #[serde(with = "ratio_dec_format")]
+    pub near_liquidation_threshold: u32,
+    /// Assets accepted as collateral. The first one is NEAR.
+    pub collateral_assets: Vec<AssetId>,
+    /// Risk parameters of the collateral assets deposited as NEP-141 tokens, every collateral
+    /// asset but NEAR, keyed by their token contract.
+    pub collateral_configs: HashMap<AssetId, CollateralConfig>,
+    /// Total debt the protocol lends out at most.
+    pub debt_ceiling: Option<U128>,
+    /// Debt a single loan may owe at most.
+    pub max_account_debt: Option<U128>,
+    /// Debt a loan that borrows must owe at least, and that repayments leave it with.
+    pub min_debt: U128,
+    /// Whether GUSD may be borrowed against isolated collateral.
+    pub borrowable_in_isolation: bool,
+    /// Collateral a liquidator repaying debt receives on top of its value.
+    #[serde(with = "ratio_dec_format")]
+    pub liquidation_bonus: u32,
+}
+
+/// Risk parameters of a collateral token. NEAR is weighted by the collateral ratio of the loan
+/// instead.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CollateralConfig {
+    /// Share of the value of the collateral that can be borrowed against.
+    #[serde(with = "ratio_dec_format")]
+    pub collateral_factor: u32,
+    /// Share of the value of the collateral the debt may reach before the loan can be
+    /// liquidated.
+    #[serde(with = "ratio_dec_format")]
+    pub liquidation_threshold: u32,
+    /// Lists the asset in isolation, capping the total debt of the loans it backs. Loans holding
+    /// an isolated asset hold no other collateral.
+    pub debt_ceiling: Option<U128>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            deposit_fee: 50,
+            borrow_fee: 0,
+            insurance_deposit_fee_share: 5000,
+            insurance_liquidation_share: 1000,
+            max_price_deviation: 500,
+            twap_window_sec: 1800,
+            max_price_change: 2000,
+            price_cooldown_sec: 3600,
+            max_price_age_sec: 300,
+            near_liquidation_threshold: 9000,
+            collateral_assets: vec!["wrap.testnet".to_string()],
+            collateral_configs: HashMap::new(),
+            debt_ceiling: None,
+            max_account_debt: None,
+            min_debt: U128(MIN_COLLATERAL_VALUE),
+            borrowable_in_isolation: true,
+            liquidation_bonus: 500,
+        }
+    }
+}
+
+impl Config {
+    pub fn assert_valid(&self) {
+        assert!(self.deposit_fee <= MAX_RATIO, "Invalid deposit fee");
+        assert!(self.borrow_fee <= MAX_RATIO, "Invalid borrow fee");
+        assert!(
+            self.insurance_deposit_fee_share <= MAX_RATIO,
+            "Invalid insurance deposit fee share"
+        );
+        assert!(
+            self.insurance_liquidation_share <= MAX_RATIO,
+            "Invalid insurance liquidation share"
+        );
+        assert!(
+            0 < self.near_liquidation_threshold && self.near_liquidation_threshold < MAX_RATIO,
+            "Invalid NEAR liquidation threshold"
+        );
+        assert!(
+            !self.collateral_assets.is_empty(),
+            "At least one collateral asset is required"
+        );
+        for asset_id in &self.collateral_assets[1..] {
+            assert!(
+                AccountId::from_str(asset_id).is_ok(),
+                "Collateral asset {} must be a token account",
+                asset_id
+            );
+            let config = self
+                .collateral_configs
+                .get(asset_id)
+                .unwrap_or_else(|| panic!("Missing collateral config for {}", asset_id));
+            assert!(
+                0 < config.collateral_factor
+                    && config.collateral_factor <= config.liquidation_threshold
+                    && config.liquidation_threshold < MAX_RATIO,
+                "Invalid collateral config for {}",
+                asset_id
+            );
+        }
+        assert!(
+            self.liquidation_bonus <= MAX_RATIO,
+            "Invalid liquidation bonus"
+        );
+    }
+}
+
+impl LendingProtocol {
+    pub(crate) fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Only contract owner can call this method"
+        );
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    pub fn get_config(&self) -> Config {
+        self.config.clone()
+    }
+
+    pub fn update_config(&mut self, config: Config) {
+        self.assert_owner();
+        config.assert_valid();
+        // Loans keep being valued with the config of every token they hold.
+        for asset_id in self.total_token_collateral.keys() {
+            assert!(
+                config.collateral_assets[1..].contains(asset_id),
+                "{} is still held as collateral",
+                asset_id
+            );
+        }
+        self.config = config;
+        self.rebuild_isolated_debt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use near_sdk::{test_utils::VMContextBuilder, testing_env};
+
+    #[test]
+    fn test_update_config_from_json() {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id("alice.near".parse().unwrap())
+            .build());
+        let mut contract = LendingProtocol::new(vec![]);
+
+        let mut config = near_sdk::serde_json::to_value(contract.get_config()).unwrap();
+        assert_eq!(config["deposit_fee"], "0.005");
+        config["deposit_fee"] = "0.0125".into();
+        config["max_price_change"] = "0.3".into();
+        contract.update_config(near_sdk::serde_json::from_value(config).unwrap());
+
+        assert_eq!(contract.config.deposit_fee, 125);
+        assert_eq!(contract.config.max_price_change, 3000);
+    }
+
+    #[test]
+    fn test_ratio_precision() {
+        let mut config = near_sdk::serde_json::to_value(Config::default()).unwrap();
+        config["deposit_fee"] = "0.00001".into();
+        assert!(near_sdk::serde_json::from_value::<Config>(config).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "wbtc.testnet is still held as collateral")]
+    fn test_remove_held_collateral_token() {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id("alice.near".parse().unwrap())
+            .build());
+        let mut contract = LendingProtocol::new(vec![]);
+        contract
+            .total_token_collateral
+            .insert("wbtc.testnet".to_string(), 100);
+        contract.update_config(Config::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid collateral config for wbtc.testnet")]
+    fn test_collateral_threshold_below_factor() {
+        let mut config = Config::default();
+        config.collateral_assets.push("wbtc.testnet".to_string());
+        config.collateral_configs.insert(
+            "wbtc.testnet".to_string(),
+            CollateralConfig {
+                collateral_factor: 8000,
+                liquidation_threshold: 7000,
+                debt_ceiling: None,
+            },
+        );
+        config.assert_valid();
+    }
+}
@@ -1,76 +1,21 @@
 use crate::*;
 
-use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::ext_contract;
 use near_sdk::json_types::U128;
-use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::Timestamp;
-
-type AssetId = String;
-pub type DurationSec = u32;
 
 pub const TGAS: u64 = 1_000_000_000_000;
 pub const NO_DEPOSIT: u128 = 0;
 pub const XCC_SUCCESS: u64 = 1;
 
-#[derive(Serialize, Deserialize)]
-#[serde(crate = "near_sdk::serde")]
-pub struct AssetPrice {
-    pub asset_id: AssetId,
-    pub price: Price,
-}
-
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
-#[serde(crate = "near_sdk::serde")]
-pub struct AssetOptionalPrice {
-    pub asset_id: AssetId,
-    pub price: Option<Price>,
-}
-
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy)]
-#[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
-#[serde(crate = "near_sdk::serde")]
-pub struct Price {
-    #[serde(with = "u128_dec_format")]
-    pub multiplier: Balance,
-    pub decimals: u8,
-}
-
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
-#[serde(crate = "near_sdk::serde")]
-pub struct PriceData {
-    #[serde(with = "u64_dec_format")]
-    pub timestamp: Timestamp,
-    pub recency_duration_sec: DurationSec,
-
-    pub prices: Vec<AssetOptionalPrice>,
-}
-
-impl Default for PriceData {
-    fn default() -> Self {
-        Self {
-            timestamp: 1,            // default value for timestamp
-            recency_duration_sec: 1, // default value for recency_duration_sec
-            prices: vec![AssetOptionalPrice {
-                asset_id: "wrap.testnet".to_string(),
-                price: Some(Price {
-                    multiplier: 15000, // default value for multiplier
-                    decimals: 6,       // default value for decimals
-                }),
-            }], // default value for prices
-        }
-    }
-}
-
 // Validator interface, for cross-contract calls
 #[ext_contract(ext_price_oracle)]
-trait PriceOracle {
+pub trait PriceOracle {
     fn get_price_data(&self, asset_ids: Option<Vec<AssetId>>) -> PriceData;
 }
 
 // USDT interface, for cross-contract calls
 #[ext_contract(ext_usdt)]
-trait Usdt {
+pub trait Usdt {
     fn ft_transfer_call(
         &mut self,
         receiver_id: AccountId,
@@ -89,48 +34,10 @@ trait Usdt {
     ) -> Promise;
 }
 
-pub mod u128_dec_format {
-    use near_sdk::serde::de;
-    use near_sdk::serde::{Deserialize, Deserializer, Serializer};
-
-    pub fn serialize<S>(num: &u128, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_str(&num.to_string())
-    }
-
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<u128, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        String::deserialize(deserializer)?
-            .parse()
-            .map_err(de::Error::custom)
-    }
-}
-
-pub mod u64_dec_format {
-    use near_sdk::serde::de;
-    use near_sdk::serde::{Deserialize, Deserializer, Serializer};
-
-    pub fn serialize<S>(num: &u64, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_str(&num.to_string())
-    }
-
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<u64, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        String::deserialize(deserializer)?
-            .parse()
-            .map_err(de::Error::custom)
-    }
-}
+// gUSDT interface, the reserve token minted and burned by the protocol
+#[ext_contract(ext_reserve_token)]
+pub trait ReserveToken {
+    fn ft_mint(&mut self, account_id: AccountId, amount: U128);
 
-pub fn to_nano(ts: u32) -> Timestamp {
-    Timestamp::from(ts) * 10u64.pow(9)
+    fn ft_burn(&mut self, account_id: AccountId, amount: U128);
 }
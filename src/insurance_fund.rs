@@ -0,0 +1,276 @@
+use crate::*;
+
+use near_sdk::Timestamp;
+
+/// Number of the latest draws kept by the insurance fund.
+pub const INSURANCE_DRAWS_SIZE: usize = 100;
+
+/// Funds set aside to cover bad debt. GUSD comes from top-ups and the borrow fees the reserve
+/// does not take, NEAR from a share of deposit fees and liquidation bonuses.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InsuranceFund {
+    /// GUSD of the fund, held by the protocol account.
+    pub stablecoin: Balance,
+    pub near: Balance,
+    /// Ring buffer of the latest draws.
+    pub draws: Vec<InsuranceDraw>,
+    /// Slot the next draw is written to once the buffer is full.
+    pub next_draw: u32,
+}
+
+impl InsuranceFund {
+    fn record_draw(&mut self, draw: InsuranceDraw) {
+        if self.draws.len() < INSURANCE_DRAWS_SIZE {
+            self.draws.push(draw);
+        } else {
+            self.draws[self.next_draw as usize] = draw;
+            self.next_draw = ((self.next_draw as usize + 1) % INSURANCE_DRAWS_SIZE) as u32;
+        }
+    }
+
+    /// Draws from the oldest to the latest.
+    fn chronological_draws(&self) -> impl Iterator<Item = &InsuranceDraw> {
+        let (newer, older) = self.draws.split_at(self.next_draw as usize);
+        older.iter().chain(newer)
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InsuranceDraw {
+    pub account_id: AccountId,
+    #[serde(with = "u64_dec_format")]
+    pub timestamp: Timestamp,
+    /// Debt repaid with the GUSD of the fund.
+    pub stablecoin: U128,
+    /// NEAR added to the collateral of the loan.
+    pub near: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct InsuranceFundView {
+    pub stablecoin: U128,
+    pub near: U128,
+    /// Number of draws kept, the latest `INSURANCE_DRAWS_SIZE` at most.
+    pub draws: u64,
+}
+
+/// Amount of NEAR worth `value` USDT at the given price, rounded up.
+pub(crate) fn near_amount_for_value(value: u128, price: &Price) -> Balance {
+    let num = U256::from(value) * U256::exp10(price.decimals as usize);
+    let multiplier = U256::from(price.multiplier);
+    ((num + multiplier - 1) / multiplier).as_u128()
+}
+
+impl LendingProtocol {
+    /// Covers the bad debt of a loan, the part of its debt its collateral is not worth. GUSD of
+    /// the fund is burned to repay the debt, NEAR of the fund tops up the collateral.
+    pub(crate) fn cover_bad_debt(
+        &mut self,
+        account_id: &AccountId,
+        loan: &mut Loan,
+        price: &Price,
+    ) {
+        let shortfall = loan.borrowed.saturating_sub(loan.collateral_value(price));
+        if shortfall == 0 {
+            return;
+        }
+
+        let stablecoin = std::cmp::min(shortfall, self.insurance_fund.stablecoin);
+        if stablecoin > 0 {
+            self.burn_stablecoin(&env::current_account_id(), stablecoin, "Bad debt");
+        }
+        self.insurance_fund.stablecoin -= stablecoin;
+        loan.borrowed -= stablecoin;
+        self.total_debt -= stablecoin;
+
+        let near = std::cmp::min(
+            near_amount_for_value(shortfall - stablecoin, price),
+            self.insurance_fund.near,
+        );
+        self.insurance_fund.near -= near;
+        loan.collateral += near;
+        self.total_collateral += near;
+
+        if stablecoin > 0 || near > 0 {
+            log!(
+                "Insurance fund covered bad debt of {}: {} GUSD, {} NEAR",
+                account_id,
+                stablecoin,
+                near
+            );
+            self.insurance_fund.record_draw(InsuranceDraw {
+                account_id: account_id.clone(),
+                timestamp: env::block_timestamp(),
+                stablecoin: U128(stablecoin),
+                near: U128(near),
+            });
+        }
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    /// Adds GUSD of the caller to the insurance fund.
+    pub fn insurance_fund_deposit(&mut self, amount: U128) {
+        self.take_stablecoin(
+            &env::predecessor_account_id(),
+            amount.0,
+            "Insurance fund deposit",
+        );
+        self.insurance_fund.stablecoin += amount.0;
+    }
+
+    pub fn get_insurance_fund(&self) -> InsuranceFundView {
+        InsuranceFundView {
+            stablecoin: U128(self.insurance_fund.stablecoin),
+            near: U128(self.insurance_fund.near),
+            draws: self.insurance_fund.draws.len() as u64,
+        }
+    }
+
+    /// Latest draws of the fund, from the oldest.
+    pub fn get_insurance_fund_draws(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<InsuranceDraw> {
+        let from_index = from_index.unwrap_or(0) as usize;
+        let limit = limit.unwrap_or(INSURANCE_DRAWS_SIZE as u64) as usize;
+        self.insurance_fund
+            .chronological_draws()
+            .skip(from_index)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+
+    /// Dave borrows `amount` GUSD against plenty of collateral.
+    fn borrow_stablecoin(contract: &mut LendingProtocol, amount: Balance) {
+        register(contract, "dave.near");
+        set_context("dave.near", amount * 1000);
+        contract.deposit_collateral();
+        set_context("dave.near", 0);
+        contract.borrow(amount);
+    }
+
+    #[test]
+    fn test_deposit_fee_share() {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+
+        set_context("bob.near", 20000);
+        contract.deposit_collateral();
+
+        // 0.5% fee, half of it to the insurance fund.
+        let bob = contract.loans.get(&"bob.near".parse().unwrap()).unwrap();
+        assert_eq!(bob.collateral, 19900);
+        assert_eq!(contract.get_insurance_fund().near.0, 50);
+    }
+
+    #[test]
+    fn test_borrow_deposit_fee() {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        register(&mut contract, "bob.near");
+
+        set_context("bob.near", 20000);
+        contract.deposit_collateral();
+        // Collateral attached to a borrow pays the deposit fee as well.
+        contract.borrow(100);
+
+        let bob = contract.loans.get(&"bob.near".parse().unwrap()).unwrap();
+        assert_eq!(bob.collateral, 2 * 19900);
+        assert_eq!(contract.total_collateral, 2 * 19900);
+        assert_eq!(contract.get_insurance_fund().near.0, 2 * 50);
+    }
+
+    #[test]
+    fn test_liquidation_bonus_share() {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+
+        register(&mut contract, "bob.near");
+        set_context("bob.near", 20000);
+        contract.deposit_collateral();
+        set_context("bob.near", 0);
+        contract.borrow(200);
+        borrow_stablecoin(&mut contract, 1000);
+        contract.stability_pool_deposit(U128(1000));
+
+        // 20000 collateral is worth 220 against a debt of 200, a bonus of 20 or ~1818 NEAR.
+        set_near_price(&mut contract, 11000);
+        contract.liquidate("bob.near".parse().unwrap());
+
+        assert_eq!(contract.get_insurance_fund().near.0, 181);
+        assert_eq!(contract.stability_pool.total_collateral, 20000 - 181);
+    }
+
+    #[test]
+    fn test_draws_keep_the_latest() {
+        let mut fund = InsuranceFund::default();
+        for i in 0..INSURANCE_DRAWS_SIZE + 3 {
+            fund.record_draw(InsuranceDraw {
+                account_id: "bob.near".parse().unwrap(),
+                timestamp: i as Timestamp,
+                stablecoin: U128(0),
+                near: U128(0),
+            });
+        }
+        assert_eq!(fund.draws.len(), INSURANCE_DRAWS_SIZE);
+        let timestamps: Vec<_> = fund.chronological_draws().map(|d| d.timestamp).collect();
+        assert_eq!(timestamps[0], 3);
+        assert_eq!(
+            timestamps[INSURANCE_DRAWS_SIZE - 1],
+            INSURANCE_DRAWS_SIZE as Timestamp + 2
+        );
+    }
+
+    #[test]
+    fn test_bad_debt_draws() {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+
+        register(&mut contract, "bob.near");
+        set_context("bob.near", 20000);
+        contract.deposit_collateral();
+        set_context("bob.near", 0);
+        contract.borrow(200);
+        borrow_stablecoin(&mut contract, 1030);
+        contract.stability_pool_deposit(U128(1000));
+        contract.insurance_fund_deposit(U128(30));
+        contract.insurance_fund.near = 5000;
+
+        // 20000 collateral is worth 150 against a debt of 200.
+        set_near_price(&mut contract, 7500);
+        contract.liquidate("bob.near".parse().unwrap());
+
+        // 30 GUSD burned, the remaining 20 are covered with 2667 NEAR.
+        let fund = contract.get_insurance_fund();
+        assert_eq!(fund.stablecoin.0, 0);
+        assert_eq!(fund.near.0, 5000 - 2667);
+        let draws = contract.get_insurance_fund_draws(None, None);
+        assert_eq!(draws.len(), 1);
+        assert_eq!(draws[0].stablecoin.0, 30);
+        assert_eq!(draws[0].near.0, 2667);
+
+        assert_eq!(contract.stability_pool.total_deposits, 830);
+        assert_eq!(contract.stability_pool.total_collateral, 22667);
+        // Only the loan of Dave is left.
+        assert_eq!(contract.total_debt, 1030);
+        assert_eq!(contract.ft_total_supply().0, 1030);
+        assert_eq!(contract.total_collateral, 1030000);
+    }
+}
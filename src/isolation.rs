@@ -0,0 +1,271 @@
+use crate::*;
+
+impl LendingProtocol {
+    /// Isolated asset backing the loan, one with a `CollateralConfig::debt_ceiling`. A loan
+    /// holding one holds no other collateral.
+    pub(crate) fn isolated_asset(&self, loan: &Loan) -> Option<AssetId> {
+        loan.token_collateral
+            .keys()
+            .find(|asset_id| self.is_isolated(asset_id))
+            .cloned()
+    }
+
+    fn is_isolated(&self, asset_id: &AssetId) -> bool {
+        self.config
+            .collateral_configs
+            .get(asset_id)
+            .is_some_and(|config| config.debt_ceiling.is_some())
+    }
+
+    /// Checks that `asset_id` can be added to the collateral of the account: isolated assets
+    /// can not be combined with any other collateral.
+    pub(crate) fn assert_collateral_combination(&self, account_id: &AccountId, asset_id: &AssetId) {
+        let loan = match self.loans.get(account_id) {
+            Some(loan) => loan,
+            None => return,
+        };
+        let is_near = asset_id == &self.config.collateral_assets[0];
+        let combined = if !is_near && self.is_isolated(asset_id) {
+            loan.collateral > 0 || loan.token_collateral.keys().any(|a| a != asset_id)
+        } else {
+            self.isolated_asset(loan).is_some()
+        };
+        assert!(
+            !combined,
+            "Isolated collateral can not be combined with other collateral"
+        );
+    }
+
+    /// Isolated asset of the loan of the account and the debt counted against its ceiling.
+    pub(crate) fn isolated_debt_of(&self, account_id: &AccountId) -> Option<(AssetId, u128)> {
+        let loan = self.loans.get(account_id)?;
+        self.isolated_asset(loan)
+            .map(|asset_id| (asset_id, loan.borrowed))
+    }
+
+    /// Counts the debt of the loan against the ceiling of its isolated asset again after its
+    /// debt or collateral changed, `before` being what `isolated_debt_of` returned before.
+    pub(crate) fn update_isolated_debt(
+        &mut self,
+        account_id: &AccountId,
+        before: Option<(AssetId, u128)>,
+    ) {
+        if let Some((asset_id, debt)) = before.filter(|(_, debt)| *debt > 0) {
+            if let Some(total) = self.isolated_debt.get_mut(&asset_id) {
+                *total = total.saturating_sub(debt);
+                if *total == 0 {
+                    self.isolated_debt.remove(&asset_id);
+                }
+            }
+        }
+        if let Some((asset_id, debt)) = self.isolated_debt_of(account_id) {
+            if debt > 0 {
+                *self.isolated_debt.entry(asset_id).or_default() += debt;
+            }
+        }
+    }
+
+    /// Recounts the debt of every isolated asset from the loans, needed once the config changes
+    /// which assets are isolated.
+    pub(crate) fn rebuild_isolated_debt(&mut self) {
+        let mut isolated_debt: HashMap<AssetId, u128> = HashMap::new();
+        for loan in self.loans.values().filter(|loan| loan.borrowed > 0) {
+            if let Some(asset_id) = self.isolated_asset(loan) {
+                *isolated_debt.entry(asset_id).or_default() += loan.borrowed;
+            }
+        }
+        self.isolated_debt = isolated_debt;
+    }
+
+    /// Checks that a loan backed by `asset_id` may borrow `amount` more: only a borrow asset
+    /// allowed in isolation, and only up to the debt ceiling of the asset.
+    pub(crate) fn assert_isolated_borrow(&self, asset_id: &AssetId, amount: u128) {
+        assert!(
+            self.config.borrowable_in_isolation,
+            "{} can not be borrowed against isolated collateral",
+            self.borrow_asset()
+        );
+        let ceiling = self.config.collateral_configs[asset_id]
+            .debt_ceiling
+            .expect("Isolated asset without a debt ceiling");
+        let debt = self
+            .isolated_debt
+            .get(asset_id)
+            .copied()
+            .unwrap_or_default();
+        assert!(
+            debt.checked_add(amount)
+                .is_some_and(|debt| debt <= ceiling.0),
+            "Debt ceiling of {} reached",
+            asset_id
+        );
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    /// Debt backed by an isolated asset.
+    pub fn get_isolated_debt(&self, asset_id: AssetId) -> U128 {
+        U128(
+            self.isolated_debt
+                .get(&asset_id)
+                .copied()
+                .unwrap_or_default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    const NEW: &str = "new.testnet";
+
+    /// Protocol accepting NEW, worth 1 USDT unit, in isolation with a debt ceiling of 100.
+    fn setup() -> LendingProtocol {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        contract.config.min_debt = U128(0);
+        contract.config.collateral_assets.push(NEW.to_string());
+        contract.config.collateral_configs.insert(
+            NEW.to_string(),
+            CollateralConfig {
+                collateral_factor: 5000,
+                liquidation_threshold: 6000,
+                debt_ceiling: Some(U128(100)),
+            },
+        );
+        let mut data = default_price_data();
+        data.prices.push(AssetOptionalPrice {
+            asset_id: NEW.to_string(),
+            price: Some(Price {
+                multiplier: 1,
+                decimals: 0,
+            }),
+        });
+        contract.price_data = Some(data);
+
+        // Keeps the protocol out of recovery mode.
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        register(&mut contract, "bob.near");
+        contract
+    }
+
+    fn deposit(contract: &mut LendingProtocol, account: &str, amount: Balance) {
+        set_context(NEW, 0);
+        contract.ft_on_transfer(account.parse().unwrap(), U128(amount), "".to_string());
+    }
+
+    #[test]
+    fn test_debt_ceiling() {
+        let mut contract = setup();
+        deposit(&mut contract, "alice.near", 1000);
+        deposit(&mut contract, "bob.near", 1000);
+
+        set_context("alice.near", 0);
+        contract.borrow(60);
+        set_context("bob.near", 0);
+        contract.borrow(40);
+        assert_eq!(contract.get_isolated_debt(NEW.to_string()).0, 100);
+
+        // Repaying frees up room under the ceiling.
+        set_context("alice.near", 0);
+        contract.repay(60);
+        assert_eq!(contract.get_isolated_debt(NEW.to_string()).0, 40);
+        set_context("alice.near", 0);
+        contract.borrow(60);
+        assert_eq!(contract.get_isolated_debt(NEW.to_string()).0, 100);
+    }
+
+    #[test]
+    fn test_ceiling_added_to_held_asset() {
+        let mut contract = setup();
+        let mut config = contract.get_config();
+        config.collateral_configs.get_mut(NEW).unwrap().debt_ceiling = None;
+        set_context("alice.near", 0);
+        contract.update_config(config.clone());
+        deposit(&mut contract, "bob.near", 1000);
+        set_context("bob.near", 0);
+        contract.borrow(40);
+        assert_eq!(contract.get_isolated_debt(NEW.to_string()).0, 0);
+
+        // The debt already backed by NEW counts against the new ceiling.
+        config.collateral_configs.get_mut(NEW).unwrap().debt_ceiling = Some(U128(100));
+        set_context("alice.near", 0);
+        contract.update_config(config);
+        assert_eq!(contract.get_isolated_debt(NEW.to_string()).0, 40);
+        set_context("bob.near", 0);
+        contract.repay(20);
+        assert_eq!(contract.get_isolated_debt(NEW.to_string()).0, 20);
+    }
+
+    #[test]
+    fn test_failed_withdrawal_restored_past_isolation() {
+        let mut contract = setup();
+        deposit(&mut contract, "bob.near", 1000);
+        set_context("bob.near", 0);
+        contract.remove_token_collateral(NEW.to_string(), U128(1000));
+        // NEAR deposited meanwhile would keep NEW from being deposited again.
+        set_context("bob.near", 10000);
+        contract.deposit_collateral();
+
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id("alice.near".parse().unwrap())
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.resolve_token_collateral_withdraw(
+            "bob.near".parse().unwrap(),
+            NEW.to_string(),
+            U128(1000),
+        );
+        let bob = &contract.loans[&"bob.near".parse().unwrap()];
+        assert_eq!(bob.token_collateral[NEW], 1000);
+        assert_eq!(contract.total_token_collateral[NEW], 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Debt ceiling of new.testnet reached")]
+    fn test_borrow_beyond_ceiling() {
+        let mut contract = setup();
+        deposit(&mut contract, "alice.near", 1000);
+        set_context("alice.near", 0);
+        contract.borrow(101);
+    }
+
+    #[test]
+    #[should_panic(expected = "Isolated collateral can not be combined with other collateral")]
+    fn test_isolated_with_near() {
+        let mut contract = setup();
+        deposit(&mut contract, "alice.near", 1000);
+        set_context("alice.near", 10000);
+        contract.deposit_collateral();
+    }
+
+    #[test]
+    #[should_panic(expected = "Isolated collateral can not be combined with other collateral")]
+    fn test_near_with_isolated() {
+        let mut contract = setup();
+        deposit(&mut contract, "carol.near", 1000);
+    }
+
+    #[test]
+    #[should_panic(expected = "can not be borrowed against isolated collateral")]
+    fn test_borrow_asset_not_allowed_in_isolation() {
+        let mut contract = setup();
+        contract.config.borrowable_in_isolation = false;
+        deposit(&mut contract, "alice.near", 1000);
+        set_context("alice.near", 0);
+        contract.borrow(10);
+    }
+}
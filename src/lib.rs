@@ -1,11 +1,42 @@
 pub mod big_decimal;
+pub mod circuit_breaker;
+pub mod collateral;
+pub mod config;
 pub mod external;
-pub mod oracle;
+pub mod insurance_fund;
+pub mod isolation;
+pub mod limits;
+pub mod liquidation;
+pub mod price;
+pub mod price_aggregation;
+pub mod recovery_mode;
+pub mod redistribution;
+pub mod reserve;
+pub mod signed_decimal;
+#[cfg(test)]
+mod simulation;
+pub mod stability_pool;
+pub mod stablecoin;
+#[cfg(test)]
+mod test_utils;
+pub mod twap;
 
 use crate::big_decimal::*;
+use crate::circuit_breaker::*;
+use crate::collateral::*;
+use crate::config::*;
 use crate::external::*;
+use crate::insurance_fund::*;
+use crate::price::*;
+use crate::price_aggregation::*;
+use crate::recovery_mode::*;
+use crate::redistribution::*;
+use crate::reserve::*;
+use crate::stability_pool::*;
+use crate::twap::*;
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::fungible_token::FungibleToken;
 use near_sdk::borsh::maybestd::collections::{HashMap, HashSet};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
@@ -15,32 +46,66 @@ use near_sdk::{env, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault,
 use std::str::FromStr;
 
 // CONSTANTS
-const USDT_CONTRACT_ID: &str = "usdt.fakes.testnet"; // TODO: update with testnet address
-                                                     // const LENDING_CONTRACT_ID: &str = "gratis_protocol.testnet"; // TODO: update with testnet address
+// const LENDING_CONTRACT_ID: &str = "gratis_protocol.testnet"; // TODO: update with testnet address
 const PRICE_ORACLE_CONTRACT_ID: &str = "priceoracle.testnet";
 const MIN_COLLATERAL_RATIO: u128 = 120;
 const LOWER_COLLATERAL_RATIO: u128 = 105;
 pub const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
 pub const GAS_FOR_FT_TRANSFER: Gas = Gas(50_000_000_000_000);
+pub const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas(10_000_000_000_000);
 pub const SAFE_GAS: Balance = 50_000_000_000_000;
 pub const MIN_COLLATERAL_VALUE: u128 = 100;
 
 #[near_bindgen]
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, PanicOnDefault)]
-#[serde(crate = "near_sdk::serde")]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct LendingProtocol {
     pub loans: HashMap<AccountId, Loan>,
     pub lower_collateral_accounts: HashSet<AccountId>,
-    pub oracle_id: AccountId,
+    pub oracle_ids: Vec<AccountId>,
     pub price_data: Option<PriceData>,
+    /// Oracles the stored `price_data` was aggregated from.
+    pub price_sources: Vec<AccountId>,
+    pub price_history: HashMap<AssetId, PriceHistory>,
+    pub circuit_breaker: CircuitBreaker,
+    pub stability_pool: StabilityPool,
+    pub reserve: Reserve,
+    /// GUSD, the stablecoin minted against the collateral.
+    pub stablecoin: FungibleToken,
+    pub total_collateral: Balance,
+    /// Token collateral of all loans, keyed by the token contract.
+    pub total_token_collateral: HashMap<AssetId, Balance>,
+    /// Debt of all loans, always the total supply of GUSD.
+    pub total_debt: u128,
+    /// Debt of the loans backed by each isolated collateral asset.
+    pub isolated_debt: HashMap<AssetId, u128>,
+    pub redistribution: Redistribution,
+    pub redistribution_pool: RedistributionPool,
+    pub insurance_fund: InsuranceFund,
+    /// NEAR collateral above the collateral ratio of loans liquidated in recovery mode, claimable
+    /// by their borrowers.
+    pub collateral_surplus: HashMap<AccountId, Balance>,
+    pub config: Config,
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy)]
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Loan {
-    pub collateral: Balance, // NOTE: this only works with NEAR as collateral currency
+    /// NEAR collateral, in yocto NEAR.
+    pub collateral: Balance,
     pub borrowed: u128,
     pub collateral_ratio: u128,
+    pub redistribution_snapshot: Redistribution,
+    /// Collateral deposited as NEP-141 tokens, keyed by their token contract.
+    pub token_collateral: HashMap<AssetId, Balance>,
+}
+
+impl Loan {
+    /// Value of the NEAR collateral in USDT at the given price, rounded down.
+    pub fn collateral_value(&self, price: &Price) -> u128 {
+        BigDecimal::from_balance_price(self.collateral, price, 0)
+            .try_round_u128_with(Rounding::Floor)
+            .expect("Collateral value overflow")
+    }
 }
 
 #[near_bindgen]
@@ -51,35 +116,18 @@ impl FungibleTokenReceiver for LendingProtocol {
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
-        // Empty message is used for stable coin depositing.
-
-        let _token_id = env::predecessor_account_id();
-
-        // Update Borrowed Balance
-        let mut loan: &mut Loan = self
-            .loans
-            .get_mut(&sender_id)
-            .expect("No collateral deposited");
-
-        // Handle transfer case when it is more than the borrowed amount
-        assert!(amount.le(&loan.borrowed.into()));
-
-        // let collateral_value: Balance = loan.collateral * price;
-
-        loan.borrowed = loan.borrowed - amount.0;
-
-        // TODO: Handle case to close the loan
-        if msg == "close" && loan.borrowed == 0 {
-            log!("Close the Loan");
-            let collateral = loan.collateral;
-            log!("Collateral: {}", collateral);
-            log!("Send back: {}", collateral - SAFE_GAS);
-            // gratis.transfer(collateral);
-            Promise::new(sender_id.clone()).transfer(collateral - SAFE_GAS);
-            loan.collateral = 0;
-            self.loans.remove(&sender_id);
+        let token_id = env::predecessor_account_id();
+        // GUSD transferred to the protocol supplies the reserve, collateral tokens are deposited
+        // with any message.
+        if token_id == env::current_account_id() {
+            assert_eq!(msg, "supply", "GUSD is only accepted to supply the reserve");
+            return PromiseOrValue::Promise(self.supply_reserve_transfer(sender_id, amount));
         }
-
+        assert!(
+            self.collateral_tokens().contains(&token_id.to_string()),
+            "Only collateral tokens are accepted"
+        );
+        self.deposit_token_collateral(&sender_id, &token_id.to_string(), amount.0);
         PromiseOrValue::Value(U128(0))
     }
 }
@@ -99,49 +147,54 @@ impl LendingProtocol {
             "Only contract owner can call this method"
         );
 
+        let mut stablecoin = FungibleToken::new(b"s".to_vec());
+        stablecoin.internal_register_account(&env::current_account_id());
+
         Self {
             loans: HashMap::new(),
             lower_collateral_accounts: lower_collateral_accounts.into_iter().collect(),
-            oracle_id: AccountId::from_str(&PRICE_ORACLE_CONTRACT_ID).unwrap(),
-            price_data: Some(PriceData::default()),
+            oracle_ids: vec![AccountId::from_str(PRICE_ORACLE_CONTRACT_ID).unwrap()],
+            price_data: Some(default_price_data()),
+            price_sources: vec![],
+            price_history: HashMap::new(),
+            circuit_breaker: CircuitBreaker::default(),
+            stability_pool: StabilityPool::default(),
+            reserve: Reserve::default(),
+            stablecoin,
+            total_collateral: 0,
+            total_token_collateral: HashMap::new(),
+            total_debt: 0,
+            isolated_debt: HashMap::new(),
+            redistribution: Redistribution::default(),
+            redistribution_pool: RedistributionPool::default(),
+            insurance_fund: InsuranceFund::default(),
+            collateral_surplus: HashMap::new(),
+            config: Config::default(),
         }
     }
 
     #[payable]
     pub fn deposit_collateral(&mut self) -> bool {
-        let deposit = env::attached_deposit();
-        let mut fee = deposit / 200; // 0.5% fee
-        let mut amount = deposit * ONE_NEAR;
-        // assert!(
-        //     deposit == amount,
-        //     "Attached deposit is not equal to the amount"
-        // );
-        fee = fee * ONE_NEAR;
-        amount -= fee;
-
+        let amount = self.take_deposit_fee(env::attached_deposit());
         assert!(amount > 0, "Deposit Amount should be greater than 0");
 
         let account_id = env::predecessor_account_id();
-        let loan: &mut Loan = self.loans.entry(account_id.clone()).or_insert(Loan {
-            collateral: 0,
-            borrowed: 0,
-            collateral_ratio: if self.lower_collateral_accounts.contains(&account_id) {
-                LOWER_COLLATERAL_RATIO
-            } else {
-                MIN_COLLATERAL_RATIO
-            },
-        });
-
-        loan.collateral += deposit;
+        self.assert_collateral_combination(&account_id, &self.config.collateral_assets[0]);
+        self.loan_entry(&account_id).collateral += amount;
+        self.total_collateral += amount;
         true
     }
 
     pub fn remove_collateral(&mut self, amount: Balance) -> bool {
+        self.assert_no_pending_price();
         let account_id = env::predecessor_account_id();
-        let loan: &mut Loan = self
+        let prices = self.collateral_prices(Valuation::Conservative);
+        self.apply_pending_redistribution(&account_id);
+        let mut loan: Loan = self
             .loans
-            .get_mut(&account_id)
-            .expect("No collateral deposited");
+            .get(&account_id)
+            .expect("No collateral deposited")
+            .clone();
 
         assert!(amount > 0, "Withdraw Amount should be greater than 0");
 
@@ -150,17 +203,27 @@ impl LendingProtocol {
             "Withdraw Amount should be less than the deposited amount"
         );
 
+        loan.collateral -= amount;
         assert!(
-            MIN_COLLATERAL_RATIO > 100 * (loan.borrowed / loan.collateral - amount),
-            "Collateral ratio should be greater than 120%"
+            self.borrowing_power(&loan, &prices) >= loan.borrowed,
+            "Collateral ratio should be greater than {}%",
+            loan.collateral_ratio
+        );
+        self.assert_system_change(
+            &prices,
+            self.total_collateral - amount,
+            &self.total_token_collateral,
+            self.total_debt,
         );
 
-        loan.collateral -= amount;
+        self.loans.insert(account_id.clone(), loan);
+        self.total_collateral -= amount;
+        Promise::new(account_id).transfer(amount);
         true
     }
 
     #[payable]
-    pub fn borrow(&mut self, usdt_amount: u128) {
+    pub fn borrow(&mut self, amount: u128) {
         /*S
            1. Calculate the collateral value
            1a. Calculate current loan value
@@ -169,30 +232,53 @@ impl LendingProtocol {
            4. If yes, then borrow the requested amount
         */
 
-        assert!(usdt_amount > 0, "Borrow Amount should be greater than 0");
+        assert!(amount > 0, "Borrow Amount should be greater than 0");
+        self.assert_no_pending_price();
 
         let account_id: AccountId = env::predecessor_account_id();
         log!("predecessor_account_id: {}", account_id);
 
-        // Get NEAR Price
-        let price = self.get_latest_price().prices[0].price.unwrap();
+        // Get the collateral prices, the lower of spot and TWAP
+        let prices = self.collateral_prices(Valuation::Conservative);
+        let price = prices.near();
 
         let near_usdt_price: u128 = price.multiplier / 10000;
         log!("price: {}", price.multiplier);
         log!("near_usdt_price: {}", near_usdt_price);
 
-        let loan: &mut Loan = self
+        self.apply_pending_redistribution(&account_id);
+        let mut loan: Loan = self
             .loans
-            .get_mut(&account_id)
-            .expect("No collateral deposited");
+            .get(&account_id)
+            .expect("No collateral deposited")
+            .clone();
+        let isolated_debt = self.isolated_debt_of(&account_id);
+
+        // The borrow fee, rounded up, is added to the debt
+        let fee = BigDecimal::from(amount)
+            .mul_with(
+                BigDecimal::from_ratio(self.config.borrow_fee),
+                Rounding::Ceil,
+            )
+            .ceil_u128();
+        let debt_amount = amount.checked_add(fee).expect("Debt overflow");
+        self.assert_borrow_limits(loan.borrowed, debt_amount);
+
+        // Collateral attached to the call is added to the loan, minus the deposit fee, before
+        // borrowing against it
+        let deposit = env::attached_deposit();
+        if deposit > 0 {
+            self.assert_collateral_combination(&account_id, &self.config.collateral_assets[0]);
+        }
+        let deposit = self.take_deposit_fee(deposit);
+        loan.collateral += deposit;
 
         // get the latest price NEAR in USDT of the collateral asset
 
         log!("raw collateral; {}", loan.collateral);
         // Calculate collateral and borrowed value
         // TODO convert to u128
-        let collateral_value: u128 =
-            BigDecimal::round_u128(&BigDecimal::from_balance_price(loan.collateral, &price, 0));
+        let collateral_value: u128 = self.loan_collateral_value(&loan, &prices);
 
         // let collateral_value: Balance = loan.collateral * price;
 
@@ -202,120 +288,251 @@ impl LendingProtocol {
         log!("borrowed_value: {}", borrowed_value);
         log!("collateral_ratio: {}", loan.collateral_ratio);
 
-        // get max borrowable amount
-        let total_max_borrowable_amount: u128 = 100u128 * collateral_value / loan.collateral_ratio;
+        // get max borrowable amount, the collateral weighted by the collateral factors
+        let total_max_borrowable_amount: u128 = self.borrowing_power(&loan, &prices);
 
-        let max_borrowable_amount = total_max_borrowable_amount
-            .checked_sub(borrowed_value)
-            .unwrap_or(0);
+        let max_borrowable_amount = total_max_borrowable_amount.saturating_sub(borrowed_value);
 
         log!("max_borrowable_amount: {}", max_borrowable_amount);
-        log!("usdt_amount: {}", usdt_amount);
+        log!("amount: {}", amount);
+        log!("fee: {}", fee);
         log!("current_account_id: {}", env::current_account_id());
 
         // If max borrowable amount is greater than the requested amount, then borrow the requested amount
-        if usdt_amount <= max_borrowable_amount {
+        if debt_amount <= max_borrowable_amount {
             // borrow the requested amount
-            let usdt_contract_account_id: AccountId =
-                AccountId::from_str(USDT_CONTRACT_ID.clone()).unwrap();
-            loan.borrowed += usdt_amount;
-            Promise::new(usdt_contract_account_id).function_call(
-                "ft_transfer".to_string(),
-                format!(
-                    r#"{{"receiver_id": "{}", "amount": "{}", "memo": "Borrowed USDT"}}"#,
-                    account_id.clone(),
-                    usdt_amount
-                )
-                .into_bytes(),
-                1,
-                Gas(50_000_000_000_000),
+            self.assert_system_change(
+                &prices,
+                self.total_collateral + deposit,
+                &self.total_token_collateral,
+                self.total_debt + debt_amount,
             );
+            if let Some((asset_id, _)) = &isolated_debt {
+                self.assert_isolated_borrow(asset_id, debt_amount);
+            }
+            loan.borrowed = loan
+                .borrowed
+                .checked_add(debt_amount)
+                .expect("Debt overflow");
+            self.total_debt = self
+                .total_debt
+                .checked_add(debt_amount)
+                .expect("Debt overflow");
+            // The borrowed GUSD is minted to the borrower and the fee to the reserve
+            self.mint_stablecoin(&account_id, amount, "Borrow");
+            if fee > 0 {
+                self.accrue_borrow_fee(fee);
+            }
         } else {
             log!("max_borrowable_amount: {}", max_borrowable_amount);
-            log!("usdt_amount: {}", usdt_amount);
+            log!("amount: {}", amount);
             // assert_eq!(false, true, "Insufficient collateral")
         }
-    }
 
-    pub fn close(&mut self, collateral: Balance, sender_id: AccountId) {
-        let loan = self.loans.get_mut(&sender_id).unwrap();
-        if loan.borrowed == 0 {
-            Promise::new(sender_id.clone()).transfer(collateral * ONE_NEAR);
-        }
+        self.loans.insert(account_id.clone(), loan);
+        self.total_collateral += deposit;
+        self.update_isolated_debt(&account_id, isolated_debt);
     }
 
-    // The "repay" method calculates the actual repayment amount and the refund amount based on the outstanding loan. If there's an overpayment, it will refund the excess amount to the user.
-    pub fn repay(&mut self, usdt_amount: u128) -> Option<Promise> {
+    // The "repay" method burns the repaid GUSD of the caller and lowers the debt by it. The debt is not repaid below the minimum debt, `close_loan` repays all of it.
+    pub fn repay(&mut self, amount: u128) -> Option<Promise> {
         /*
-          1. Calculate the collateral value
-          2. Calculate current loaned value
+          1. Calculate current loaned value
           2. Calculate max repay amount
-          3. Check if the max repay amount is greater than the requested amount
-          4. If yes, then repay the requested amount
+          3. Repay the requested amount, at most the max repay amount
+          4. Burn the repaid amount
         */
 
-        assert!(usdt_amount > 0, "Repay Amount should be greater than 0");
+        assert!(amount > 0, "Repay Amount should be greater than 0");
 
         let predecessor_account_id: AccountId = env::predecessor_account_id();
 
-        let price = self.get_latest_price().prices[0].price.unwrap();
-
-        let loan: &mut Loan = self
+        self.apply_pending_redistribution(&predecessor_account_id);
+        let isolated_debt = self.isolated_debt_of(&predecessor_account_id);
+        let borrowed_value: u128 = self
             .loans
-            .get_mut(&predecessor_account_id)
-            .expect("No collateral deposited");
-        // get the latest price NEAR in USDT of the collateral asset
-
-        // Calculate collateral and borrowed value
-        let _collateral_value: u128 =
-            BigDecimal::round_u128(&BigDecimal::from_balance_price(loan.collateral, &price, 0));
+            .get(&predecessor_account_id)
+            .expect("No collateral deposited")
+            .borrowed;
+
+        // The collateral ratio is the one required of the account and stays as it is.
+        let min_debt = self.config.min_debt.0;
+        let repaid = if amount.saturating_add(min_debt) <= borrowed_value {
+            amount
+        } else {
+            // They overpaid. Only the debt above the minimum is repaid
+            borrowed_value.saturating_sub(min_debt)
+        };
+        let total_debt = self.total_debt.checked_sub(repaid).expect("Debt underflow");
+        // GUSD is repaid by burning it from the caller, it is never transferred to the protocol.
+        self.burn_stablecoin(&predecessor_account_id, repaid, "Repay");
+        let loan: &mut Loan = self.loans.get_mut(&predecessor_account_id).unwrap();
+        loan.borrowed -= repaid;
+        self.total_debt = total_debt;
+        self.update_isolated_debt(&predecessor_account_id, isolated_debt);
+        // Fix return
+        None
+    }
 
-        let borrowed_value: u128 = loan.borrowed;
+    /// Burns the whole debt of the caller out of their GUSD and returns all of their
+    /// collateral. Like a withdrawal, closing a loan is subject to `assert_system_change`.
+    ///
+    /// Token collateral the account can not receive is put back on a loan of the account.
+    pub fn close_loan(&mut self) {
+        let account_id = env::predecessor_account_id();
+        let prices = self.collateral_prices(Valuation::Conservative);
+        self.apply_pending_redistribution(&account_id);
+        let isolated_debt = self.isolated_debt_of(&account_id);
+        let loan = self
+            .loans
+            .get(&account_id)
+            .expect("No collateral deposited")
+            .clone();
+        let total_debt = self
+            .total_debt
+            .checked_sub(loan.borrowed)
+            .expect("Debt underflow");
+
+        let mut total_token_collateral = self.total_token_collateral.clone();
+        for (asset_id, amount) in &loan.token_collateral {
+            *total_token_collateral
+                .get_mut(asset_id)
+                .expect("No such collateral") -= amount;
+        }
+        self.assert_system_change(
+            &prices,
+            self.total_collateral - loan.collateral,
+            &total_token_collateral,
+            total_debt,
+        );
 
-        // If max borrowable amount is greater than the requested amount, then borrow the requested amount
-        if usdt_amount + MIN_COLLATERAL_VALUE <= borrowed_value {
-            loan.borrowed -= usdt_amount;
-            // Recalculate collateral ratio
-            loan.collateral_ratio = _collateral_value / loan.borrowed;
-            // Fix return
-            None
-        } else {
-            // They overpaid. Protocol will return the full collateral in NEAR
-            loan.borrowed = MIN_COLLATERAL_VALUE;
-            loan.collateral_ratio = _collateral_value / loan.borrowed;
-            None
-            // Some(Promise::new(predecessor_account_id.clone()).transfer(collateral_to_return))
+        let collateral = loan.collateral;
+        let send_back = collateral.saturating_sub(SAFE_GAS);
+        self.burn_stablecoin(&account_id, loan.borrowed, "Close loan");
+        self.total_debt = total_debt;
+        self.loans.get_mut(&account_id).unwrap().borrowed = 0;
+        self.update_isolated_debt(&account_id, isolated_debt);
+
+        log!("Close the Loan");
+        log!("Collateral: {}", collateral);
+        log!("Send back: {}", send_back);
+        if send_back > 0 {
+            Promise::new(account_id.clone()).transfer(send_back);
+        }
+        for (asset_id, amount) in loan.token_collateral {
+            self.take_token_collateral(&account_id, &asset_id, amount);
+            self.send_collateral(account_id.clone(), &asset_id, amount)
+                .then(
+                    Self::ext(env::current_account_id())
+                        .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                        .resolve_token_collateral_withdraw(
+                            account_id.clone(),
+                            asset_id,
+                            U128(amount),
+                        ),
+                );
         }
+        self.loans.remove(&account_id);
+        self.total_collateral -= collateral;
     }
 
     /* -----------------------------------------------------------------------------------
     ------------------------------------ GETTERS -----------------------------------------
     -------------------------------------------------------------------------------------- */
 
+    /// All loans, including the redistributed debt and collateral they have not picked up yet.
     pub fn get_all_loans(&self) -> HashMap<AccountId, Loan> {
-        return self.loans.clone();
+        self.loans
+            .iter()
+            .map(|(account_id, loan)| {
+                let (collateral, debt) = self.redistribution.pending(loan);
+                let mut loan = loan.clone();
+                loan.collateral += collateral;
+                loan.borrowed += debt;
+                loan.redistribution_snapshot = self.redistribution;
+                (account_id.clone(), loan)
+            })
+            .collect()
     }
 
+    /// Queries all oracles in parallel for the configured assets, `get_price_callback` joins
+    /// their results.
     pub fn get_prices(&self) -> Promise {
-        let gas: Gas = Gas(50_000_000_000_000);
-
-        ext_price_oracle::ext(self.oracle_id.clone())
-            .with_static_gas(gas)
-            .get_price_data(Some(vec![
-                "wrap.testnet".to_string(),
-                "usdt.fakes.testnet".to_string(),
-            ]))
-            .then(Self::ext(env::current_account_id()).get_price_callback())
+        self.assert_owner();
+        let asset_ids = self.price_asset_ids();
+        self.oracle_ids
+            .iter()
+            .map(|oracle_id| {
+                ext_price_oracle::ext(oracle_id.clone())
+                    .with_static_gas(GAS_FOR_GET_PRICE_DATA)
+                    .get_price_data(Some(asset_ids.clone()))
+            })
+            .reduce(|promise, next| promise.and(next))
+            .expect("No oracles configured")
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_PRICE_CALLBACK)
+                    .get_price_callback(self.oracle_ids.clone()),
+            )
     }
 
+    /// Stores the median of the oracle prices, unless the sources disagree by more than the
+    /// configured deviation or the circuit breaker holds the update back, in which cases the
+    /// previous price is kept. So is it if any collateral asset is left without a price.
     #[private]
-    pub fn get_price_callback(&mut self, #[callback] data: PriceData) -> PriceData {
-        self.price_data = Some(data.clone());
-        data
+    pub fn get_price_callback(&mut self, oracle_ids: Vec<AccountId>) -> PriceData {
+        let (sources, results) = self.price_data_results(&oracle_ids);
+        let asset_ids = self.price_asset_ids();
+        let aggregated =
+            aggregate_price_data(&results, &asset_ids, self.config.max_price_deviation).and_then(
+                |data| match self.config.collateral_assets.iter().find(|asset_id| {
+                    !data
+                        .prices
+                        .iter()
+                        .any(|p| &p.asset_id == *asset_id && p.price.is_some())
+                }) {
+                    Some(asset_id) => Err(format!("No price for {}", asset_id)),
+                    None => Ok(data),
+                },
+            );
+        match aggregated {
+            Ok(data) => {
+                if self.apply_price_update(data) {
+                    self.price_sources = sources;
+                }
+                self.get_latest_price()
+            }
+            Err(err) => {
+                log!("Price update rejected: {}", err);
+                self.get_latest_price()
+            }
+        }
     }
 
     pub fn get_latest_price(&self) -> PriceData {
-        return self.price_data.clone().unwrap();
+        self.price_data.clone().unwrap()
+    }
+}
+
+impl LendingProtocol {
+    /// Takes the deposit fee out of a NEAR deposit, crediting its share to the insurance fund.
+    /// Returns the collateral left for the loan.
+    fn take_deposit_fee(&mut self, deposit: Balance) -> Balance {
+        // The fee is rounded up so the credited collateral is rounded down.
+        let fee = BigDecimal::from(deposit)
+            .mul_with(
+                BigDecimal::from_ratio(self.config.deposit_fee),
+                Rounding::Ceil,
+            )
+            .ceil_u128();
+        self.insurance_fund.near +=
+            fee * self.config.insurance_deposit_fee_share as u128 / MAX_RATIO as u128;
+        deposit - fee
+    }
+
+    /// Latest NEAR price, the first entry of the stored `PriceData`.
+    fn near_price(&self) -> Price {
+        self.get_latest_price().prices[0].price.unwrap()
     }
 }
 
@@ -323,17 +540,10 @@ impl LendingProtocol {
 mod tests {
     use super::*;
 
+    use crate::test_utils::*;
     use near_sdk::{test_utils::VMContextBuilder, testing_env, AccountId};
 
     // Auxiliar fn: create a mock context
-    fn set_context(predecessor: &str, amount: Balance) {
-        let mut builder = VMContextBuilder::new();
-        builder.predecessor_account_id(predecessor.parse().unwrap());
-        builder.attached_deposit(amount);
-
-        testing_env!(builder.build());
-    }
-
     #[test]
     pub fn initialize() {
         let a: AccountId = "alice.near".parse().unwrap();
@@ -342,20 +552,21 @@ mod tests {
             .predecessor_account_id(a.clone())
             .build());
         let contract: LendingProtocol = LendingProtocol::new(vec![a.clone()]);
-        assert_eq!(contract.oracle_id, "priceoracle.testnet".parse().unwrap())
+        assert_eq!(
+            contract.oracle_ids,
+            vec!["priceoracle.testnet".parse().unwrap()]
+        )
     }
 
     #[test]
     pub fn test_get_usdt() {
         let a: AccountId = "alice.near".parse().unwrap();
-        let v: Vec<AccountId> = vec![a.clone()];
         testing_env!(VMContextBuilder::new()
             .predecessor_account_id(a.clone())
             .build());
 
         let contract: LendingProtocol = LendingProtocol::new(vec![a.clone()]);
-        let usdt_amount: Balance = 100;
-        let p = contract.get_prices();
+        let _p = contract.get_prices();
         // let result = contract.get_usdt_callback(); // Replace with actual callback method
         // println!("{:?}", result.prices.first().unwrap().price);
 
@@ -371,14 +582,13 @@ mod tests {
     #[test]
     pub fn test_borrow() {
         let a: AccountId = "alice.near".parse().unwrap();
-        let bob: AccountId = "bob.near".parse().unwrap();
-        let v: Vec<AccountId> = vec![a.clone()];
         testing_env!(VMContextBuilder::new()
             .predecessor_account_id(a.clone())
             .signer_account_id(a.clone())
             .build());
 
         let mut contract: LendingProtocol = LendingProtocol::new(vec![a.clone()]);
+        contract.config.min_debt = U128(0);
         let collateral_amount: Balance = 10000;
         let borrow_amount: Balance = 50;
 
@@ -399,8 +609,6 @@ mod tests {
     #[test]
     pub fn test_repay() {
         let a: AccountId = "alice.near".parse().unwrap();
-        let bob: AccountId = "bob.near".parse().unwrap();
-        let v: Vec<AccountId> = vec![a.clone()];
         testing_env!(VMContextBuilder::new()
             .predecessor_account_id(a.clone())
             .signer_account_id(a.clone())
@@ -426,3 +634,55 @@ mod tests {
         assert_eq!(loan.borrowed, MIN_COLLATERAL_VALUE);
     }
 }
+
+#[cfg(test)]
+mod proptests {
+    use super::*;
+
+    use crate::test_utils::*;
+    use num_bigint::BigUint;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn max_borrow_within_collateral_ratio(
+            collateral in 1..10u128.pow(30),
+            multiplier in 1..10u128.pow(9),
+            decimals in 6..=30u8,
+            lower_ratio in any::<bool>(),
+            amounts in proptest::collection::vec(1..10u128.pow(12), 1..5),
+        ) {
+            // Every case starts from empty storage, GUSD balances live there.
+            near_sdk::mock::with_mocked_blockchain(|b| b.take_storage());
+            set_context("alice.near", 0);
+            let borrower: AccountId = "bob.near".parse().unwrap();
+            let lower = if lower_ratio { vec![borrower.clone()] } else { vec![] };
+            let mut contract = LendingProtocol::new(lower);
+            contract.config.min_debt = U128(0);
+            let mut data = default_price_data();
+            data.prices[0].price = Some(Price { multiplier, decimals });
+            contract.price_data = Some(data);
+            register(&mut contract, "bob.near");
+
+            // A loan without debt keeps the protocol out of recovery mode.
+            set_context("carol.near", collateral * 100);
+            contract.deposit_collateral();
+            set_context("bob.near", collateral);
+            contract.deposit_collateral();
+
+            set_context("bob.near", 0);
+            for amount in amounts {
+                contract.borrow(amount);
+                let loan = contract.loans[&borrower].clone();
+                // borrowed * ratio <= 100 * collateral * multiplier / 10^decimals
+                let debt = BigUint::from(loan.borrowed)
+                    * BigUint::from(loan.collateral_ratio)
+                    * BigUint::from(10u8).pow(decimals as u32);
+                let value = BigUint::from(100u8)
+                    * BigUint::from(loan.collateral)
+                    * BigUint::from(multiplier);
+                prop_assert!(debt <= value);
+            }
+        }
+    }
+}
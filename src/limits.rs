@@ -0,0 +1,148 @@
+use crate::*;
+
+/// Borrowing limits of the protocol and, for a given account, the debt that can still be taken on
+/// under each of them. `None` stands for no limit.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Limits {
+    pub min_debt: U128,
+    pub debt_ceiling: Option<U128>,
+    pub remaining_debt_ceiling: Option<U128>,
+    pub max_account_debt: Option<U128>,
+    pub remaining_account_debt: Option<U128>,
+    /// The least of the remaining amounts.
+    pub remaining_capacity: Option<U128>,
+}
+
+impl LendingProtocol {
+    /// Checks that a loan owing `debt` may borrow `amount` more within the configured limits.
+    pub(crate) fn assert_borrow_limits(&self, debt: u128, amount: u128) {
+        let total_debt = self.total_debt.checked_add(amount).expect("Debt overflow");
+        let debt = debt.checked_add(amount).expect("Debt overflow");
+        assert!(
+            debt >= self.config.min_debt.0,
+            "Debt should be at least {}",
+            self.config.min_debt.0
+        );
+        if let Some(ceiling) = self.config.debt_ceiling {
+            assert!(
+                total_debt <= ceiling.0,
+                "Borrow exceeds the protocol debt ceiling"
+            );
+        }
+        if let Some(max_debt) = self.config.max_account_debt {
+            assert!(
+                debt <= max_debt.0,
+                "Borrow exceeds the maximum debt per account"
+            );
+        }
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    /// Borrowing limits and the capacity left under them, for `account_id` if given.
+    pub fn get_limits(&self, account_id: Option<AccountId>) -> Limits {
+        let debt = account_id
+            .and_then(|account_id| self.get_all_loans().remove(&account_id))
+            .map_or(0, |loan| loan.borrowed);
+        let remaining =
+            |limit: Option<u128>, debt: u128| limit.map(|limit| limit.saturating_sub(debt));
+
+        let debt_ceiling = self.config.debt_ceiling.map(|ceiling| ceiling.0);
+        let max_account_debt = self.config.max_account_debt.map(|max_debt| max_debt.0);
+        let remaining_debt_ceiling = remaining(debt_ceiling, self.total_debt);
+        let remaining_account_debt = remaining(max_account_debt, debt);
+        let remaining_capacity = [remaining_debt_ceiling, remaining_account_debt]
+            .into_iter()
+            .flatten()
+            .min();
+
+        Limits {
+            min_debt: self.config.min_debt,
+            debt_ceiling: debt_ceiling.map(U128),
+            remaining_debt_ceiling: remaining_debt_ceiling.map(U128),
+            max_account_debt: max_account_debt.map(U128),
+            remaining_account_debt: remaining_account_debt.map(U128),
+            remaining_capacity: remaining_capacity.map(U128),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+
+    /// Alice and Bob each hold collateral worth 1500 and owe 100.
+    fn setup() -> LendingProtocol {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        register(&mut contract, "bob.near");
+        register(&mut contract, "carol.near");
+        for account in ["alice.near", "bob.near"] {
+            set_context(account, 100000);
+            contract.deposit_collateral();
+            set_context(account, 0);
+            contract.borrow(100);
+        }
+        contract.config.debt_ceiling = Some(U128(400));
+        contract.config.max_account_debt = Some(U128(250));
+        contract
+    }
+
+    #[test]
+    fn test_get_limits() {
+        let contract = setup();
+
+        let limits = contract.get_limits(Some("alice.near".parse().unwrap()));
+        assert_eq!(limits.min_debt.0, MIN_COLLATERAL_VALUE);
+        assert_eq!(limits.remaining_debt_ceiling.unwrap().0, 200);
+        assert_eq!(limits.remaining_account_debt.unwrap().0, 150);
+        assert_eq!(limits.remaining_capacity.unwrap().0, 150);
+
+        let limits = contract.get_limits(None);
+        assert_eq!(limits.remaining_account_debt.unwrap().0, 250);
+        assert_eq!(limits.remaining_capacity.unwrap().0, 200);
+    }
+
+    #[test]
+    fn test_borrow_up_to_limits() {
+        let mut contract = setup();
+        set_context("alice.near", 0);
+        contract.borrow(150);
+        set_context("bob.near", 0);
+        contract.borrow(50);
+        assert_eq!(contract.total_debt, 400);
+        assert_eq!(contract.get_limits(None).remaining_capacity.unwrap().0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Borrow exceeds the maximum debt per account")]
+    fn test_max_account_debt() {
+        let mut contract = setup();
+        set_context("alice.near", 0);
+        contract.borrow(151);
+    }
+
+    #[test]
+    #[should_panic(expected = "Borrow exceeds the protocol debt ceiling")]
+    fn test_debt_ceiling() {
+        let mut contract = setup();
+        contract.config.debt_ceiling = Some(U128(250));
+        set_context("alice.near", 0);
+        contract.borrow(51);
+    }
+
+    #[test]
+    #[should_panic(expected = "Debt should be at least 100")]
+    fn test_min_debt() {
+        let mut contract = setup();
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        set_context("carol.near", 0);
+        contract.borrow(99);
+    }
+}
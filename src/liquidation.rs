@@ -0,0 +1,292 @@
+use crate::*;
+
+/// Amount of an asset worth `value` USDT plus the liquidation `bonus` at the given price,
+/// rounded down.
+fn seized_amount(value: u128, bonus: u32, price: &Price) -> Balance {
+    let num =
+        U256::from(value) * U256::from(MAX_RATIO + bonus) * U256::exp10(price.decimals as usize);
+    (num / (U256::from(MAX_RATIO) * U256::from(price.multiplier))).as_u128()
+}
+
+/// Debt a seized `amount` repays, the inverse of `seized_amount` rounded up.
+fn repaid_value(amount: Balance, bonus: u32, price: &Price) -> u128 {
+    let num = U256::from(amount) * U256::from(price.multiplier) * U256::from(MAX_RATIO);
+    let den = U256::exp10(price.decimals as usize) * U256::from(MAX_RATIO + bonus);
+    ((num + den - 1) / den).as_u128()
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    /// Liquidates a loan whose collateral weighted by the liquidation thresholds no longer
+    /// covers its debt, or whose collateral ratio is below the total one in recovery mode.
+    ///
+    /// Bad debt, the part of the debt the collateral is not worth, is first covered by the
+    /// insurance fund. The debt is then cancelled against the stability pool and the matching
+    /// share of the NEAR collateral is handed to the pool depositors, minus the insurance fund
+    /// share of the liquidation bonus. Whatever the pool cannot absorb is redistributed to the
+    /// remaining loans.
+    ///
+    /// In recovery mode, only the collateral worth the debt at the collateral ratio of the loan
+    /// is liquidated, the rest is left for the borrower to claim with `claim_collateral_surplus`.
+    ///
+    /// Loans are valued at the TWAP so a short price swing cannot trigger liquidations. Loans
+    /// holding token collateral are liquidated by repaying their debt with
+    /// `liquidate_with_repay` instead.
+    pub fn liquidate(&mut self, account_id: AccountId) {
+        let prices = self.liquidation_prices();
+        let price = *prices.near();
+        self.apply_pending_redistribution(&account_id);
+        let mut loan = self
+            .loans
+            .get(&account_id)
+            .expect("No collateral deposited")
+            .clone();
+        assert!(
+            self.is_loan_liquidatable(&loan, &prices),
+            "Loan is not liquidatable"
+        );
+        assert!(
+            loan.token_collateral.is_empty(),
+            "Loans with token collateral are liquidated by repaying their debt"
+        );
+
+        let recovery_mode = self.is_recovery_mode(&prices);
+
+        self.cover_bad_debt(&account_id, &mut loan, &price);
+        if recovery_mode {
+            self.take_collateral_surplus(&account_id, &mut loan, &price);
+        }
+
+        let debt_to_offset = std::cmp::min(loan.borrowed, self.stability_pool.total_deposits);
+        let mut collateral_to_seize = 0;
+        if debt_to_offset > 0 {
+            collateral_to_seize = (U256::from(loan.collateral) * U256::from(debt_to_offset)
+                / U256::from(loan.borrowed))
+            .as_u128();
+            let bonus =
+                collateral_to_seize.saturating_sub(near_amount_for_value(debt_to_offset, &price));
+            let insurance_share =
+                bonus * self.config.insurance_liquidation_share as u128 / MAX_RATIO as u128;
+            self.insurance_fund.near += insurance_share;
+            self.stability_pool
+                .offset(debt_to_offset, collateral_to_seize - insurance_share);
+            loan.borrowed -= debt_to_offset;
+            loan.collateral -= collateral_to_seize;
+            self.total_debt -= debt_to_offset;
+            // The GUSD of the pool deposits that absorbed the debt is burned.
+            self.burn_stablecoin(
+                &env::current_account_id(),
+                debt_to_offset,
+                "Stability pool offset",
+            );
+            self.total_collateral -= collateral_to_seize;
+        }
+
+        log!(
+            "Liquidated {}: {} debt offset, {} collateral seized, {} debt and {} collateral redistributed",
+            account_id,
+            debt_to_offset,
+            collateral_to_seize,
+            loan.borrowed,
+            loan.collateral
+        );
+
+        if loan.borrowed > 0 {
+            self.redistribute(&account_id, loan.borrowed, loan.collateral);
+            self.loans.remove(&account_id);
+        } else if loan.collateral == 0 {
+            self.loans.remove(&account_id);
+        } else {
+            self.loans.insert(account_id, loan);
+        }
+    }
+
+    /// Repays up to `amount` of the debt of a liquidatable loan with GUSD of the caller, who
+    /// receives the `collateral_asset` collateral worth the repaid debt plus the liquidation
+    /// bonus. If the loan holds less of that asset, only the debt it covers is repaid. Returns
+    /// the repaid debt, the GUSD burned.
+    ///
+    /// In recovery mode the seized collateral is capped at the value of the repaid debt at the
+    /// collateral ratio of the loan, as for `liquidate`.
+    ///
+    /// Token collateral the liquidator can not receive, for instance because they are not
+    /// registered with the token, is put back on the loan.
+    pub fn liquidate_with_repay(
+        &mut self,
+        account_id: AccountId,
+        collateral_asset: AssetId,
+        amount: U128,
+    ) -> U128 {
+        let liquidator = env::predecessor_account_id();
+        let amount = amount.0;
+        let prices = self.liquidation_prices();
+        self.apply_pending_redistribution(&account_id);
+        let isolated_debt = self.isolated_debt_of(&account_id);
+        let loan = self
+            .loans
+            .get(&account_id)
+            .expect("No collateral deposited");
+        assert!(
+            self.is_loan_liquidatable(loan, &prices),
+            "Loan is not liquidatable"
+        );
+
+        let is_near = collateral_asset == self.config.collateral_assets[0];
+        let (price, balance) = if is_near {
+            (*prices.near(), loan.collateral)
+        } else {
+            (
+                *prices.token(&collateral_asset),
+                loan.token_collateral
+                    .get(&collateral_asset)
+                    .copied()
+                    .unwrap_or_default(),
+            )
+        };
+        let bonus = self.config.liquidation_bonus;
+        let mut repay = std::cmp::min(amount, loan.borrowed);
+        let mut seized = seized_amount(repay, bonus, &price);
+        if seized > balance {
+            seized = balance;
+            repay = std::cmp::min(repay, repaid_value(seized, bonus, &price));
+        }
+        if self.is_recovery_mode(&prices) {
+            seized = std::cmp::min(
+                seized,
+                recovery_seizure_cap(repay, loan.collateral_ratio, &price),
+            );
+        }
+        assert!(repay > 0 && seized > 0, "Nothing to liquidate");
+
+        self.burn_stablecoin(&liquidator, repay, "Liquidation");
+        let loan = self.loans.get_mut(&account_id).unwrap();
+        loan.borrowed -= repay;
+        self.total_debt -= repay;
+        if is_near {
+            loan.collateral -= seized;
+            self.total_collateral -= seized;
+        } else {
+            self.take_token_collateral(&account_id, &collateral_asset, seized);
+        }
+        self.update_isolated_debt(&account_id, isolated_debt);
+        let loan = &self.loans[&account_id];
+        if loan.borrowed == 0 && loan.collateral == 0 && loan.token_collateral.is_empty() {
+            self.loans.remove(&account_id);
+        }
+
+        log!(
+            "{} liquidated {}: {} debt repaid, {} {} seized",
+            liquidator,
+            account_id,
+            repay,
+            seized,
+            collateral_asset
+        );
+        let transfer = self.send_collateral(liquidator, &collateral_asset, seized);
+        if !is_near {
+            transfer.then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .resolve_token_collateral_withdraw(account_id, collateral_asset, U128(seized)),
+            );
+        }
+        U128(repay)
+    }
+}
+
+impl LendingProtocol {
+    /// TWAP collateral prices, checking liquidations are not paused by the circuit breaker.
+    fn liquidation_prices(&self) -> CollateralPrices {
+        assert!(
+            !self.are_liquidations_paused(),
+            "Liquidations are paused until {}",
+            self.circuit_breaker.liquidations_paused_until
+        );
+        self.collateral_prices(Valuation::Twap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_liquidate_against_stability_pool() {
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let bob: AccountId = "bob.near".parse().unwrap();
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+
+        set_context("alice.near", 10000);
+        contract.deposit_collateral();
+        set_context("alice.near", 0);
+        contract.borrow(100);
+
+        register(&mut contract, "bob.near");
+        set_context("bob.near", 1000000);
+        contract.deposit_collateral();
+        set_context("bob.near", 0);
+        contract.borrow(1000);
+        contract.stability_pool_deposit(U128(1000));
+
+        // 10000 yocto at 0.01 is worth 100, below the 120% ratio.
+        set_near_price(&mut contract, 10000);
+        set_context("carol.near", 0);
+        contract.liquidate(alice.clone());
+
+        assert!(!contract.loans.contains_key(&alice));
+        let deposit = contract.get_stability_pool_deposit(bob).unwrap();
+        assert_eq!(deposit.deposit.0, 900);
+        assert_eq!(deposit.collateral_gain.0, 10000);
+    }
+
+    #[test]
+    fn test_near_liquidation_threshold() {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec!["bob.near".parse().unwrap()]);
+        contract.config.deposit_fee = 0;
+        contract.config.min_debt = U128(0);
+        // Collateral without debt keeps the protocol out of recovery mode.
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        for account_id in ["alice.near", "bob.near"] {
+            register(&mut contract, account_id);
+            set_context(account_id, 10000);
+            contract.deposit_collateral();
+            set_context(account_id, 0);
+            contract.borrow(100);
+        }
+        let liquidatable = |contract: &LendingProtocol, account_id: &str| {
+            let prices = contract.collateral_prices(Valuation::Spot);
+            contract.is_loan_liquidatable(&contract.loans[&account_id.parse().unwrap()], &prices)
+        };
+
+        // At 115% Alice is below her 120% ratio but above the 90% threshold.
+        set_near_price(&mut contract, 11500);
+        assert!(!liquidatable(&contract, "alice.near"));
+        set_near_price(&mut contract, 11000);
+        assert!(liquidatable(&contract, "alice.near"));
+        // Bob borrows at 105%, past the threshold, and is only liquidated below it.
+        assert!(!liquidatable(&contract, "bob.near"));
+        set_near_price(&mut contract, 10400);
+        assert!(liquidatable(&contract, "bob.near"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Loan is not liquidatable")]
+    fn test_liquidate_healthy_loan() {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+
+        set_context("alice.near", 10000);
+        contract.deposit_collateral();
+        set_context("alice.near", 0);
+        contract.borrow(100);
+
+        contract.liquidate("alice.near".parse().unwrap());
+    }
+}
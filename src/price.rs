@@ -0,0 +1,57 @@
+use crate::*;
+
+pub use gratis_common::*;
+
+/// Price data the contract starts with, until the first `get_prices`.
+pub(crate) fn default_price_data() -> PriceData {
+    PriceData {
+        timestamp: 1,            // default value for timestamp
+        recency_duration_sec: 1, // default value for recency_duration_sec
+        prices: vec![AssetOptionalPrice {
+            asset_id: "wrap.testnet".to_string(),
+            price: Some(Price {
+                multiplier: 15000, // default value for multiplier
+                decimals: 6,       // default value for decimals
+            }),
+        }], // default value for prices
+    }
+}
+
+/// Values of a `Price` in the precision of `BigDecimal`.
+pub trait PriceValue {
+    fn to_big_decimal(&self) -> BigDecimal;
+
+    /// Price of one unit of this asset in units of the asset priced at `quote`.
+    fn cross_rate(&self, quote: &Price) -> BigDecimal;
+}
+
+impl PriceValue for Price {
+    fn to_big_decimal(&self) -> BigDecimal {
+        BigDecimal::from_balance_price(1, self, 0)
+    }
+
+    fn cross_rate(&self, quote: &Price) -> BigDecimal {
+        self.to_big_decimal() / quote.to_big_decimal()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price(multiplier: u128, decimals: u8) -> Price {
+        Price {
+            multiplier,
+            decimals,
+        }
+    }
+
+    #[test]
+    fn test_cross_rate() {
+        // The smallest NEAR unit is worth 0.015, the smallest USDT unit 0.000001.
+        let near = price(15000, 6);
+        let usdt = price(1, 6);
+        assert_eq!(near.cross_rate(&usdt).round_u128(), 15000);
+        assert_eq!(near.to_big_decimal().round_mul_u128(1000), 15);
+    }
+}
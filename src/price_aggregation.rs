@@ -0,0 +1,262 @@
+use crate::*;
+
+use near_sdk::PromiseResult;
+
+pub const GAS_FOR_GET_PRICE_DATA: Gas = Gas(20_000_000_000_000);
+pub const GAS_FOR_PRICE_CALLBACK: Gas = Gas(20_000_000_000_000);
+
+/// Combines the price data returned by several oracles into the median price of every asset.
+/// Assets no source reported a price for are left without one.
+///
+/// Fails if the prices reported for any asset spread further apart than `max_deviation`
+/// (in basis points of the median).
+pub(crate) fn aggregate_price_data(
+    sources: &[PriceData],
+    asset_ids: &[AssetId],
+    max_deviation: u32,
+) -> Result<PriceData, String> {
+    let mut prices = Vec::with_capacity(asset_ids.len());
+    for asset_id in asset_ids {
+        let reported: Vec<Price> = sources
+            .iter()
+            .filter_map(|data| {
+                data.prices
+                    .iter()
+                    .find(|p| &p.asset_id == asset_id)
+                    .and_then(|p| p.price)
+            })
+            .collect();
+        let price = match median(reported.clone()) {
+            Some(price) => price,
+            None => {
+                prices.push(AssetOptionalPrice {
+                    asset_id: asset_id.clone(),
+                    price: None,
+                });
+                continue;
+            }
+        };
+
+        let decimals = reported.iter().map(|p| p.decimals).max().unwrap();
+        let values: Vec<U384> = reported
+            .iter()
+            .map(|p| p.normalized_multiplier(decimals))
+            .collect();
+        let spread = *values.iter().max().unwrap() - *values.iter().min().unwrap();
+        if spread * U384::from(MAX_RATIO)
+            > price.normalized_multiplier(decimals) * U384::from(max_deviation)
+        {
+            return Err(format!("Price sources for {} deviate too much", asset_id));
+        }
+
+        prices.push(AssetOptionalPrice {
+            asset_id: asset_id.clone(),
+            price: Some(price),
+        });
+    }
+
+    Ok(PriceData {
+        timestamp: sources.iter().map(|data| data.timestamp).min().unwrap_or(0),
+        recency_duration_sec: sources
+            .iter()
+            .map(|data| data.recency_duration_sec)
+            .min()
+            .unwrap_or(0),
+        prices,
+    })
+}
+
+impl LendingProtocol {
+    /// Assets priced by `get_prices`, the collateral assets. GUSD is valued at one USD.
+    pub(crate) fn price_asset_ids(&self) -> Vec<AssetId> {
+        self.config.collateral_assets.clone()
+    }
+
+    /// Returns true if every price is valid and non-zero, and the data is at most
+    /// `max_price_age_sec` old.
+    fn is_valid_price_data(&self, data: &PriceData) -> bool {
+        let recent =
+            data.timestamp + to_nano(self.config.max_price_age_sec) >= env::block_timestamp();
+        recent
+            && data
+                .prices
+                .iter()
+                .filter_map(|p| p.price)
+                .all(|price| price.is_valid() && price.multiplier > 0)
+    }
+
+    /// Collects the `PriceData` returned by every oracle queried in `get_prices`, skipping the
+    /// ones that failed or returned invalid or stale prices.
+    ///
+    /// The results are read directly rather than through `#[callback_result]` arguments since
+    /// the number of oracles is only known at runtime.
+    pub(crate) fn price_data_results(
+        &self,
+        oracle_ids: &[AccountId],
+    ) -> (Vec<AccountId>, Vec<PriceData>) {
+        (0..env::promise_results_count())
+            .zip(oracle_ids)
+            .filter_map(|(i, oracle_id)| {
+                let data = match env::promise_result(i) {
+                    PromiseResult::Successful(data) => {
+                        near_sdk::serde_json::from_slice::<PriceData>(&data).ok()
+                    }
+                    _ => None,
+                };
+                match data {
+                    Some(data) if self.is_valid_price_data(&data) => {
+                        Some((oracle_id.clone(), data))
+                    }
+                    _ => {
+                        log!("Price oracle {} failed", oracle_id);
+                        None
+                    }
+                }
+            })
+            .unzip()
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    pub fn set_oracles(&mut self, oracle_ids: Vec<AccountId>) {
+        self.assert_owner();
+        assert!(!oracle_ids.is_empty(), "At least one oracle is required");
+        self.oracle_ids = oracle_ids;
+    }
+
+    pub fn get_oracles(&self) -> Vec<AccountId> {
+        self.oracle_ids.clone()
+    }
+
+    /// Oracles whose prices made up the latest stored `PriceData`.
+    pub fn get_price_sources(&self) -> Vec<AccountId> {
+        self.price_sources.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use near_sdk::{test_utils::VMContextBuilder, testing_env, RuntimeFeesConfig, VMConfig};
+
+    fn price_data(multiplier: u128, decimals: u8) -> PriceData {
+        PriceData {
+            timestamp: 10,
+            recency_duration_sec: 90,
+            prices: vec![AssetOptionalPrice {
+                asset_id: "wrap.testnet".to_string(),
+                price: Some(Price {
+                    multiplier,
+                    decimals,
+                }),
+            }],
+        }
+    }
+
+    fn set_promise_results(results: Vec<PromiseResult>, timestamp: u64) {
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id("alice.near".parse().unwrap())
+                .block_timestamp(timestamp)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            results,
+        );
+    }
+
+    fn successful(data: PriceData) -> PromiseResult {
+        PromiseResult::Successful(near_sdk::serde_json::to_vec(&data).unwrap())
+    }
+
+    fn setup() -> LendingProtocol {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id("alice.near".parse().unwrap())
+            .build());
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.set_oracles(vec![
+            "oracle1.near".parse().unwrap(),
+            "oracle2.near".parse().unwrap(),
+            "oracle3.near".parse().unwrap(),
+        ]);
+        contract
+    }
+
+    #[test]
+    fn test_median_of_sources() {
+        let mut contract = setup();
+        set_promise_results(
+            vec![
+                successful(price_data(15000, 6)),
+                PromiseResult::Failed,
+                successful(price_data(1520, 5)),
+            ],
+            0,
+        );
+
+        let data = contract.get_price_callback(contract.get_oracles());
+        assert_eq!(data.prices[0].price.unwrap().multiplier, 15000);
+        assert_eq!(contract.near_price().multiplier, 15000);
+        assert_eq!(
+            contract.get_price_sources(),
+            vec![
+                "oracle1.near".parse().unwrap(),
+                "oracle3.near".parse::<AccountId>().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_and_invalid_sources_skipped() {
+        let mut contract = setup();
+        let mut stale = price_data(30000, 6);
+        stale.timestamp = 0;
+        set_promise_results(
+            vec![
+                PromiseResult::Failed,
+                successful(price_data(1000, 78)),
+                successful(stale),
+            ],
+            to_nano(contract.config.max_price_age_sec) + 1,
+        );
+
+        let data = contract.get_price_callback(contract.get_oracles());
+        assert_eq!(data.prices[0].price.unwrap().multiplier, 15000);
+        assert!(contract.get_price_sources().is_empty());
+    }
+
+    #[test]
+    fn test_deviation_keeps_previous_price() {
+        let mut contract = setup();
+        set_promise_results(
+            vec![
+                successful(price_data(15000, 6)),
+                successful(price_data(15100, 6)),
+                successful(price_data(17000, 6)),
+            ],
+            0,
+        );
+
+        contract.get_price_callback(contract.get_oracles());
+        assert_eq!(contract.near_price().multiplier, 15000);
+        assert!(contract.get_price_sources().is_empty());
+
+        contract.config.max_price_deviation = 2000;
+        contract.get_price_callback(contract.get_oracles());
+        assert_eq!(contract.near_price().multiplier, 15100);
+        assert_eq!(contract.get_price_sources().len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only contract owner can call this method")]
+    fn test_get_prices_owner_only() {
+        let contract = setup();
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id("bob.near".parse().unwrap())
+            .build());
+        contract.get_prices();
+    }
+}
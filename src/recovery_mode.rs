@@ -0,0 +1,308 @@
+use crate::*;
+
+/// Below this total collateral ratio (in percent) the protocol is in recovery mode.
+pub const RECOVERY_COLLATERAL_RATIO: u128 = 150;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SystemStatus {
+    pub total_collateral: U128,
+    pub total_collateral_value: U128,
+    pub total_debt: U128,
+    /// Total collateral ratio in percent, `None` while nothing is borrowed.
+    pub total_collateral_ratio: Option<BigDecimal>,
+    pub recovery_mode: bool,
+}
+
+/// Returns true if `value_a / debt_a < value_b / debt_b`, treating a zero debt as an
+/// infinite ratio.
+fn ratio_lt(value_a: u128, debt_a: u128, value_b: u128, debt_b: u128) -> bool {
+    if debt_a == 0 {
+        return false;
+    }
+    if debt_b == 0 {
+        return true;
+    }
+    U256::from(value_a) * U256::from(debt_b) < U256::from(value_b) * U256::from(debt_a)
+}
+
+/// Amount of collateral at `price` worth `debt` at the collateral ratio `ratio`, the most a
+/// liquidation in recovery mode may seize for that debt. Rounded up.
+pub(crate) fn recovery_seizure_cap(debt: u128, ratio: u128, price: &Price) -> Balance {
+    let value = (U256::from(debt) * U256::from(ratio) + 99) / 100;
+    near_amount_for_value(value.as_u128(), price)
+}
+
+impl LendingProtocol {
+    /// Value of the NEAR and token collateral of all loans.
+    pub(crate) fn total_collateral_value(&self, prices: &CollateralPrices) -> u128 {
+        self.collateral_totals_value(prices, self.total_collateral, &self.total_token_collateral)
+    }
+
+    pub(crate) fn is_recovery_mode(&self, prices: &CollateralPrices) -> bool {
+        ratio_lt(
+            self.total_collateral_value(prices),
+            self.total_debt,
+            RECOVERY_COLLATERAL_RATIO,
+            100,
+        )
+    }
+
+    /// Checks that moving the system totals to `total_collateral`, `total_token_collateral` and
+    /// `total_debt` is allowed: outside of recovery mode the change may not trigger it, and in
+    /// recovery mode it must improve the total collateral ratio.
+    pub(crate) fn assert_system_change(
+        &self,
+        prices: &CollateralPrices,
+        total_collateral: Balance,
+        total_token_collateral: &HashMap<AssetId, Balance>,
+        total_debt: u128,
+    ) {
+        let value = self.total_collateral_value(prices);
+        let new_value =
+            self.collateral_totals_value(prices, total_collateral, total_token_collateral);
+        if self.is_recovery_mode(prices) {
+            assert!(
+                ratio_lt(value, self.total_debt, new_value, total_debt),
+                "Recovery mode: the operation must improve the total collateral ratio"
+            );
+        } else {
+            assert!(
+                !ratio_lt(new_value, total_debt, RECOVERY_COLLATERAL_RATIO, 100),
+                "The operation would put the protocol into recovery mode"
+            );
+        }
+    }
+
+    /// A loan is liquidatable once its debt exceeds its collateral weighted by the liquidation
+    /// thresholds and, in recovery mode, below the total collateral ratio as well.
+    pub(crate) fn is_loan_liquidatable(&self, loan: &Loan, prices: &CollateralPrices) -> bool {
+        (loan.borrowed > 0 && self.liquidation_limit(loan, prices) < loan.borrowed)
+            || (self.is_recovery_mode(prices)
+                && ratio_lt(
+                    self.loan_collateral_value(loan, prices),
+                    loan.borrowed,
+                    self.total_collateral_value(prices),
+                    self.total_debt,
+                ))
+    }
+
+    /// Sets aside the NEAR collateral of a loan liquidated in recovery mode that exceeds its
+    /// collateral ratio, so the liquidation only seizes what the debt at that ratio is worth.
+    pub(crate) fn take_collateral_surplus(
+        &mut self,
+        account_id: &AccountId,
+        loan: &mut Loan,
+        price: &Price,
+    ) {
+        let surplus = loan.collateral.saturating_sub(recovery_seizure_cap(
+            loan.borrowed,
+            loan.collateral_ratio,
+            price,
+        ));
+        if surplus == 0 {
+            return;
+        }
+        loan.collateral -= surplus;
+        self.total_collateral -= surplus;
+        *self
+            .collateral_surplus
+            .entry(account_id.clone())
+            .or_default() += surplus;
+        log!("{} collateral of {} is left to claim", surplus, account_id);
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    pub fn get_system_status(&self) -> SystemStatus {
+        let prices = self.collateral_prices(Valuation::Spot);
+        let total_collateral_value = self.total_collateral_value(&prices);
+        SystemStatus {
+            total_collateral: U128(self.total_collateral),
+            total_collateral_value: U128(total_collateral_value),
+            total_debt: U128(self.total_debt),
+            total_collateral_ratio: (self.total_debt > 0).then(|| {
+                BigDecimal::from(total_collateral_value) * BigDecimal::from(100u32)
+                    / BigDecimal::from(self.total_debt)
+            }),
+            recovery_mode: self.is_recovery_mode(&prices),
+        }
+    }
+
+    pub fn get_collateral_surplus(&self, account_id: AccountId) -> U128 {
+        U128(
+            self.collateral_surplus
+                .get(&account_id)
+                .copied()
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Sends the caller the collateral surplus left by the liquidation of their loan in recovery
+    /// mode.
+    pub fn claim_collateral_surplus(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let surplus = self
+            .collateral_surplus
+            .remove(&account_id)
+            .expect("No collateral surplus to claim");
+        Promise::new(account_id).transfer(surplus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+
+    fn setup() -> LendingProtocol {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        contract.config.min_debt = U128(0);
+
+        // Both loans hold collateral worth 150 at the default price.
+        set_context("alice.near", 10000);
+        contract.deposit_collateral();
+        set_context("alice.near", 0);
+        contract.borrow(100);
+
+        register(&mut contract, "bob.near");
+        set_context("bob.near", 10000);
+        contract.deposit_collateral();
+        set_context("bob.near", 0);
+        contract.borrow(90);
+        contract
+    }
+
+    #[test]
+    fn test_system_status() {
+        let mut contract = setup();
+
+        let status = contract.get_system_status();
+        assert_eq!(status.total_collateral.0, 20000);
+        assert_eq!(status.total_debt.0, 190);
+        assert_eq!(status.total_collateral_ratio.unwrap().round_u128(), 158);
+        assert!(!status.recovery_mode);
+
+        set_near_price(&mut contract, 10000);
+        let status = contract.get_system_status();
+        assert_eq!(status.total_collateral_ratio.unwrap().round_u128(), 105);
+        assert!(status.recovery_mode);
+    }
+
+    #[test]
+    #[should_panic(expected = "The operation would put the protocol into recovery mode")]
+    fn test_borrow_into_recovery_mode() {
+        let mut contract = setup();
+
+        set_context("bob.near", 0);
+        contract.borrow(20);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Recovery mode: the operation must improve the total collateral ratio"
+    )]
+    fn test_recovery_mode_blocks_withdrawal() {
+        let mut contract = setup();
+        set_near_price(&mut contract, 13000);
+
+        set_context("bob.near", 0);
+        contract.remove_collateral(1000);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Recovery mode: the operation must improve the total collateral ratio"
+    )]
+    fn test_recovery_mode_rejects_unchanged_ratio() {
+        let mut contract = setup();
+        set_near_price(&mut contract, 13000);
+
+        // Bob adds collateral worth 104 and borrows 76, exactly the TCR of 260 for 190 debt.
+        set_context("bob.near", 8000);
+        contract.borrow(76);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Recovery mode: the operation must improve the total collateral ratio"
+    )]
+    fn test_recovery_mode_blocks_closing_healthier_loan() {
+        let mut contract = setup();
+        set_near_price(&mut contract, 13000);
+
+        // Bob is at 144%, above the TCR of 137%, closing his loan lowers it.
+        set_context("bob.near", 0);
+        contract.close_loan();
+    }
+
+    #[test]
+    fn test_recovery_mode_borrow_with_collateral() {
+        let mut contract = setup();
+        set_near_price(&mut contract, 13000);
+
+        set_context("bob.near", 10000);
+        contract.borrow(10);
+        let bob = contract.loans.get(&"bob.near".parse().unwrap()).unwrap();
+        assert_eq!(bob.collateral, 20000);
+        assert_eq!(bob.borrowed, 100);
+    }
+
+    #[test]
+    fn test_recovery_mode_liquidates_below_tcr() {
+        let mut contract = setup();
+        // Alice is at 130% and Bob at 144%, both above their 120% ratio, while the TCR is 137%.
+        set_near_price(&mut contract, 13000);
+
+        let prices = contract.collateral_prices(Valuation::Spot);
+        let alice = &contract.loans[&"alice.near".parse().unwrap()];
+        let bob = &contract.loans[&"bob.near".parse().unwrap()];
+        assert!(contract.liquidation_limit(alice, &prices) >= alice.borrowed);
+        assert!(contract.is_loan_liquidatable(alice, &prices));
+        assert!(!contract.is_loan_liquidatable(bob, &prices));
+    }
+
+    #[test]
+    fn test_recovery_mode_liquidation_leaves_surplus() {
+        let mut contract = setup();
+        let alice: AccountId = "alice.near".parse().unwrap();
+        set_context("bob.near", 0);
+        contract.stability_pool_deposit(U128(90));
+        set_near_price(&mut contract, 13000);
+
+        // At 120% Alice's 100 debt is worth 9231 of her 10000 collateral.
+        set_context("carol.near", 0);
+        contract.liquidate(alice.clone());
+        assert_eq!(contract.get_collateral_surplus(alice.clone()).0, 769);
+        let deposit = contract
+            .get_stability_pool_deposit("bob.near".parse().unwrap())
+            .unwrap();
+        // 8307 seized for the 90 GUSD of the pool, minus the insurance share of the bonus.
+        assert_eq!(deposit.collateral_gain.0, 8168);
+        assert_eq!(contract.total_collateral, 10924);
+
+        set_context("alice.near", 0);
+        contract.claim_collateral_surplus();
+        assert_eq!(contract.get_collateral_surplus(alice).0, 0);
+    }
+
+    #[test]
+    fn test_recovery_mode_repay_liquidation_capped_at_loan_ratio() {
+        let mut contract = setup();
+        contract.config.liquidation_bonus = 3000;
+        set_near_price(&mut contract, 13000);
+
+        // 50 repaid with a 30% bonus would seize 5000, at Alice's 120% ratio it is worth 4616.
+        set_context("bob.near", 0);
+        let near = contract.config.collateral_assets[0].clone();
+        let repaid = contract.liquidate_with_repay("alice.near".parse().unwrap(), near, U128(50));
+        assert_eq!(repaid.0, 50);
+        let alice = &contract.loans[&"alice.near".parse().unwrap()];
+        assert_eq!(alice.collateral, 10000 - 4616);
+        assert_eq!(alice.borrowed, 50);
+    }
+}
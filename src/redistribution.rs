@@ -0,0 +1,195 @@
+use crate::*;
+
+/// Debt and collateral redistributed from liquidated loans, accumulated per unit of collateral.
+///
+/// Every loan keeps a copy taken the last time it was touched; the difference to the current
+/// values times the loan collateral is what the loan still has to take over.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Redistribution {
+    pub collateral_per_unit: BigDecimal,
+    pub debt_per_unit: BigDecimal,
+}
+
+/// Redistributed debt and collateral that no loan has picked up yet, the way Liquity keeps them
+/// in its default pool. Shares of loans are rounded down, so some dust may stay here for good.
+///
+/// The per-unit values are rounded down as well, the remainders of their divisions are carried
+/// into the next redistribution.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RedistributionPool {
+    pub collateral: Balance,
+    pub debt: u128,
+    pub collateral_error: BigDecimal,
+    pub debt_error: BigDecimal,
+}
+
+/// Adds `amount` plus the carried `error` spread over `total_stakes` to `per_unit`, rounded
+/// down, and keeps what the rounding left out in `error`.
+fn distribute(
+    per_unit: &mut BigDecimal,
+    error: &mut BigDecimal,
+    amount: u128,
+    total_stakes: BigDecimal,
+) {
+    let numerator = BigDecimal::from(amount)
+        .checked_add(*error)
+        .expect("Redistribution overflow");
+    let share = numerator
+        .checked_div_with(total_stakes, Rounding::Floor)
+        .expect("Redistribution overflow");
+    // Exact, the stakes are whole numbers.
+    *error = numerator - share.mul_with(total_stakes, Rounding::Floor);
+    *per_unit = per_unit
+        .checked_add(share)
+        .expect("Redistribution overflow");
+}
+
+impl Redistribution {
+    /// Collateral and debt the loan has been assigned since its snapshot was taken, rounded
+    /// down so that loans never take more than was redistributed.
+    pub fn pending(&self, loan: &Loan) -> (Balance, u128) {
+        let stake = BigDecimal::from(loan.collateral);
+        let snapshot = &loan.redistribution_snapshot;
+        let share = |per_unit: BigDecimal, snapshot: BigDecimal| {
+            stake
+                .checked_mul_with(per_unit.saturating_sub(snapshot), Rounding::Floor)
+                .and_then(|share| share.try_round_u128_with(Rounding::Floor))
+                .expect("Redistribution overflow")
+        };
+        (
+            share(self.collateral_per_unit, snapshot.collateral_per_unit),
+            share(self.debt_per_unit, snapshot.debt_per_unit),
+        )
+    }
+}
+
+impl LendingProtocol {
+    /// Moves the redistributed collateral and debt the loan has accumulated from the pool into
+    /// the loan.
+    pub(crate) fn apply_pending_redistribution(&mut self, account_id: &AccountId) {
+        let redistribution = self.redistribution;
+        if let Some(loan) = self.loans.get_mut(account_id) {
+            let (collateral, debt) = redistribution.pending(loan);
+            loan.collateral += collateral;
+            loan.borrowed += debt;
+            loan.redistribution_snapshot = redistribution;
+            self.redistribution_pool.collateral -= collateral;
+            self.redistribution_pool.debt -= debt;
+        }
+    }
+
+    /// Spreads debt and collateral of a liquidated loan over all other loans, pro-rata to their
+    /// collateral. Both wait in the redistribution pool until the loans are touched, system
+    /// totals are unchanged.
+    pub(crate) fn redistribute(&mut self, liquidated: &AccountId, debt: u128, collateral: Balance) {
+        let total_stakes: Balance = self
+            .loans
+            .iter()
+            .filter(|(account_id, _)| *account_id != liquidated)
+            .map(|(_, loan)| loan.collateral)
+            .sum();
+        assert!(total_stakes > 0, "No loans to redistribute the debt to");
+
+        let total_stakes = BigDecimal::from(total_stakes);
+        let pool = &mut self.redistribution_pool;
+        distribute(
+            &mut self.redistribution.collateral_per_unit,
+            &mut pool.collateral_error,
+            collateral,
+            total_stakes,
+        );
+        distribute(
+            &mut self.redistribution.debt_per_unit,
+            &mut pool.debt_error,
+            debt,
+            total_stakes,
+        );
+        pool.collateral += collateral;
+        pool.debt += debt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+
+    fn open_loan(
+        contract: &mut LendingProtocol,
+        account_id: &str,
+        collateral: Balance,
+        debt: u128,
+    ) {
+        register(contract, account_id);
+        set_context(account_id, collateral);
+        contract.deposit_collateral();
+        set_context(account_id, 0);
+        contract.borrow(debt);
+    }
+
+    #[test]
+    fn test_redistribute_to_remaining_loans() {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        contract.config.min_debt = U128(0);
+        open_loan(&mut contract, "bob.near", 30000, 100);
+        open_loan(&mut contract, "carol.near", 10000, 10);
+        open_loan(&mut contract, "alice.near", 10000, 120);
+
+        set_near_price(&mut contract, 13000);
+        contract.liquidate("alice.near".parse().unwrap());
+
+        // Bob holds 3/4 and Carol 1/4 of the remaining collateral.
+        let loans = contract.get_all_loans();
+        let bob = loans.get(&"bob.near".parse().unwrap()).unwrap();
+        let carol = loans.get(&"carol.near".parse().unwrap()).unwrap();
+        assert_eq!((bob.collateral, bob.borrowed), (37500, 190));
+        assert_eq!((carol.collateral, carol.borrowed), (12500, 40));
+        assert_eq!(contract.total_debt, 230);
+        assert_eq!(contract.total_collateral, 50000);
+
+        // Stored loans only pick up their share once touched.
+        let bob_id: AccountId = "bob.near".parse().unwrap();
+        assert_eq!(contract.loans.get(&bob_id).unwrap().borrowed, 100);
+        set_context("bob.near", 0);
+        contract.borrow(1);
+        assert_eq!(contract.loans.get(&bob_id).unwrap().borrowed, 191);
+        assert_eq!(contract.loans.get(&bob_id).unwrap().collateral, 37500);
+    }
+
+    #[test]
+    fn test_rounding_dust_stays_in_pool() {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        contract.config.min_debt = U128(0);
+        open_loan(&mut contract, "bob.near", 10000, 10);
+        open_loan(&mut contract, "carol.near", 20000, 10);
+        open_loan(&mut contract, "alice.near", 10000, 121);
+
+        set_near_price(&mut contract, 13000);
+        contract.liquidate("alice.near".parse().unwrap());
+
+        // A third of 10000 and 121 each, rounded down.
+        let loans = contract.get_all_loans();
+        let bob = &loans[&"bob.near".parse().unwrap()];
+        let carol = &loans[&"carol.near".parse().unwrap()];
+        assert_eq!((bob.collateral, bob.borrowed), (13333, 50));
+        assert_eq!((carol.collateral, carol.borrowed), (26666, 90));
+
+        for account_id in ["bob.near", "carol.near"] {
+            contract.apply_pending_redistribution(&account_id.parse().unwrap());
+        }
+        let pool = contract.redistribution_pool;
+        assert_eq!((pool.collateral, pool.debt), (1, 1));
+        let loans = contract.loans.values();
+        let collateral: Balance = loans.clone().map(|loan| loan.collateral).sum();
+        let debt: u128 = loans.map(|loan| loan.borrowed).sum();
+        assert_eq!(collateral + pool.collateral, contract.total_collateral);
+        assert_eq!(debt + pool.debt, contract.total_debt);
+    }
+}
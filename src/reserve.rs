@@ -0,0 +1,467 @@
+use crate::*;
+
+use near_sdk::is_promise_success;
+
+pub const GAS_FOR_RESERVE_TOKEN: Gas = Gas(10_000_000_000_000);
+pub const GAS_FOR_RESOLVE_RESERVE: Gas = Gas(10_000_000_000_000);
+
+/// GUSD supplied by lenders to earn the borrow fees.
+///
+/// Suppliers hold shares of the reserve as gUSDT, a NEP-141 token deployed on the `gusdt`
+/// sub-account of the protocol, which mints it on supply and burns it on redemption. A share is
+/// worth its pro-rata part of the GUSD the reserve holds in the protocol account. Borrow fees are
+/// minted into the reserve while it has suppliers, raising the value of every share, and go to
+/// the insurance fund otherwise.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Reserve {
+    /// Shares minted as gUSDT, including the ones being minted.
+    pub total_shares: Balance,
+    /// GUSD supplied plus the fees accrued to the suppliers.
+    pub assets: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReserveView {
+    /// Account of the gUSDT token.
+    pub token_id: AccountId,
+    pub assets: U128,
+    pub total_shares: U128,
+    /// GUSD per share.
+    pub exchange_rate: BigDecimal,
+}
+
+impl Reserve {
+    /// GUSD the shares can be redeemed for, rounded down.
+    pub fn shares_value(&self, shares: Balance) -> Balance {
+        if self.total_shares == 0 {
+            return 0;
+        }
+        (U256::from(shares) * U256::from(self.assets) / U256::from(self.total_shares)).as_u128()
+    }
+
+    /// GUSD a share is worth, rising as the fees accrue. A share is worth one GUSD until the
+    /// first supply.
+    pub fn exchange_rate(&self) -> BigDecimal {
+        if self.total_shares == 0 {
+            return BigDecimal::from(1u32);
+        }
+        BigDecimal::from(self.assets) / BigDecimal::from(self.total_shares)
+    }
+
+    /// Adds `amount` of GUSD and returns the shares it is worth, rounded down.
+    pub fn supply(&mut self, amount: Balance) -> Balance {
+        assert!(amount > 0, "Deposit Amount should be greater than 0");
+        let shares = if self.total_shares == 0 {
+            amount
+        } else {
+            (U256::from(amount) * U256::from(self.total_shares) / U256::from(self.assets)).as_u128()
+        };
+        assert!(shares > 0, "Deposit Amount is worth no shares");
+        self.total_shares += shares;
+        self.assets += amount;
+        shares
+    }
+
+    /// Takes back a supply whose shares could not be minted. The fees accrued meanwhile stay
+    /// with the other shares.
+    pub fn cancel_supply(&mut self, amount: Balance, shares: Balance) {
+        self.total_shares -= shares;
+        self.assets -= amount;
+    }
+
+    /// Removes burned `shares` and returns the GUSD they are worth.
+    pub fn redeem(&mut self, shares: Balance) -> Balance {
+        let amount = self.shares_value(shares);
+        self.total_shares -= shares;
+        self.assets -= amount;
+        amount
+    }
+}
+
+impl LendingProtocol {
+    /// Account of the gUSDT token, the `gusdt` sub-account of the protocol.
+    pub(crate) fn reserve_token_id(&self) -> AccountId {
+        AccountId::from_str(&format!("gusdt.{}", env::current_account_id())).unwrap()
+    }
+
+    /// Mints the shares a supply of `amount` is worth to `account_id` as gUSDT.
+    fn mint_reserve_shares(&mut self, account_id: AccountId, amount: Balance) -> (Promise, U128) {
+        let shares = self.reserve.supply(amount);
+        let promise = ext_reserve_token::ext(self.reserve_token_id())
+            .with_static_gas(GAS_FOR_RESERVE_TOKEN)
+            .ft_mint(account_id, U128(shares));
+        (promise, U128(shares))
+    }
+
+    /// Supplies GUSD transferred with `ft_transfer_call`, which refunds it if the shares could
+    /// not be minted.
+    pub(crate) fn supply_reserve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        let (promise, shares) = self.mint_reserve_shares(sender_id, amount.0);
+        promise.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_RESERVE)
+                .resolve_reserve_supply_transfer(amount, shares),
+        )
+    }
+
+    /// Credits a borrow fee, minted to the protocol account, to the reserve suppliers or, without
+    /// any, to the insurance fund.
+    pub(crate) fn accrue_borrow_fee(&mut self, fee: Balance) {
+        self.mint_stablecoin(&env::current_account_id(), fee, "Borrow fee");
+        if self.reserve.total_shares > 0 {
+            self.reserve.assets += fee;
+        } else {
+            self.insurance_fund.stablecoin += fee;
+        }
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    /// Supplies GUSD of the caller to the reserve, who receives the shares as gUSDT and must be
+    /// registered with it. Returns the shares received, none if they could not be minted, in
+    /// which case the GUSD is returned.
+    pub fn reserve_supply(&mut self, amount: U128) -> Promise {
+        let account_id = env::predecessor_account_id();
+        self.take_stablecoin(&account_id, amount.0, "Reserve supply");
+        let (promise, shares) = self.mint_reserve_shares(account_id.clone(), amount.0);
+        promise.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_RESERVE)
+                .resolve_reserve_supply(account_id, amount, shares),
+        )
+    }
+
+    #[private]
+    pub fn resolve_reserve_supply(
+        &mut self,
+        account_id: AccountId,
+        amount: U128,
+        shares: U128,
+    ) -> U128 {
+        if is_promise_success() {
+            return shares;
+        }
+        log!(
+            "Minting {} gUSDT to {} failed, returning the GUSD",
+            shares.0,
+            account_id
+        );
+        self.reserve.cancel_supply(amount.0, shares.0);
+        self.give_stablecoin(&account_id, amount.0, "Reserve supply refund");
+        U128(0)
+    }
+
+    /// Returns the GUSD of a supply through `ft_transfer_call` left unused, all of it if the
+    /// shares could not be minted.
+    #[private]
+    pub fn resolve_reserve_supply_transfer(&mut self, amount: U128, shares: U128) -> U128 {
+        if is_promise_success() {
+            return U128(0);
+        }
+        log!("Minting {} gUSDT failed, refunding the GUSD", shares.0);
+        self.reserve.cancel_supply(amount.0, shares.0);
+        amount
+    }
+
+    /// Burns `shares` of the gUSDT of the caller and pays out the GUSD they are worth once
+    /// burned. Returns the GUSD received.
+    pub fn reserve_redeem(&mut self, shares: U128) -> Promise {
+        let account_id = env::predecessor_account_id();
+        self.assert_stablecoin_registered(&account_id);
+        assert!(
+            self.reserve.shares_value(shares.0) > 0,
+            "Withdraw Amount should be greater than 0"
+        );
+        ext_reserve_token::ext(self.reserve_token_id())
+            .with_static_gas(GAS_FOR_RESERVE_TOKEN)
+            .ft_burn(account_id.clone(), shares)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_RESERVE)
+                    .resolve_reserve_redeem(account_id, shares),
+            )
+    }
+
+    #[private]
+    pub fn resolve_reserve_redeem(&mut self, account_id: AccountId, shares: U128) -> U128 {
+        assert!(is_promise_success(), "Burning the gUSDT failed");
+        let amount = self.reserve.redeem(shares.0);
+        self.give_stablecoin(&account_id, amount, "Reserve withdrawal");
+        U128(amount)
+    }
+
+    /// GUSD `shares` of gUSDT can be redeemed for.
+    pub fn get_reserve_shares_value(&self, shares: U128) -> U128 {
+        U128(self.reserve.shares_value(shares.0))
+    }
+
+    pub fn get_reserve(&self) -> ReserveView {
+        ReserveView {
+            token_id: self.reserve_token_id(),
+            assets: U128(self.reserve.assets),
+            total_shares: U128(self.reserve.total_shares),
+            exchange_rate: self.reserve.exchange_rate(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    /// Mocks the callback of the protocol for a gUSDT call with the given result.
+    fn set_token_result(result: PromiseResult) {
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id("alice.near".parse().unwrap())
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
+    /// Supplies GUSD of the account, with the gUSDT minted. Returns the shares.
+    fn supply(contract: &mut LendingProtocol, account_id: &str, amount: u128) -> u128 {
+        let total_shares = contract.reserve.total_shares;
+        set_context(account_id, 0);
+        contract.reserve_supply(U128(amount));
+        let shares = contract.reserve.total_shares - total_shares;
+        set_token_result(PromiseResult::Successful(vec![]));
+        contract
+            .resolve_reserve_supply(account_id.parse().unwrap(), U128(amount), U128(shares))
+            .0
+    }
+
+    /// Lends 100 GUSD to Carol without a borrow fee.
+    fn fund_carol(contract: &mut LendingProtocol) {
+        contract.config.borrow_fee = 0;
+        register(contract, "carol.near");
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        set_context("carol.near", 0);
+        contract.borrow(100);
+    }
+
+    /// Receivers and names of the calls of the receipts created so far.
+    fn calls() -> Vec<(String, String)> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| {
+                let receiver_id = receipt.receiver_id.to_string();
+                receipt
+                    .actions
+                    .into_iter()
+                    .filter_map(move |action| match action {
+                        VmAction::FunctionCall { function_name, .. } => {
+                            Some((receiver_id.clone(), function_name))
+                        }
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    /// Dave supplies 300 GUSD and Bob 100, borrowed with a 10% borrow fee.
+    fn setup() -> LendingProtocol {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        contract.config.borrow_fee = 1000;
+        for (account_id, amount) in [("dave.near", 300), ("bob.near", 100)] {
+            register(&mut contract, account_id);
+            set_context(account_id, 100000);
+            contract.deposit_collateral();
+            set_context(account_id, 0);
+            contract.borrow(amount);
+        }
+        // Without suppliers the fees of these borrows went to the insurance fund.
+        assert_eq!(contract.get_insurance_fund().stablecoin.0, 40);
+
+        supply(&mut contract, "dave.near", 300);
+        supply(&mut contract, "bob.near", 100);
+        contract
+    }
+
+    #[test]
+    fn test_supply_mints_gusdt() {
+        let mut contract = setup();
+        fund_carol(&mut contract);
+        set_context("carol.near", 0);
+        contract.reserve_supply(U128(50));
+        assert_eq!(
+            calls(),
+            [
+                ("gusdt.alice.near".to_string(), "ft_mint".to_string()),
+                (
+                    "alice.near".to_string(),
+                    "resolve_reserve_supply".to_string()
+                )
+            ]
+        );
+        assert_eq!(contract.get_reserve().total_shares.0, 450);
+        assert_eq!(contract.ft_balance_of("carol.near".parse().unwrap()).0, 50);
+    }
+
+    #[test]
+    fn test_fees_raise_share_value() {
+        let mut contract = setup();
+        register(&mut contract, "carol.near");
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        set_context("carol.near", 0);
+        contract.borrow(200);
+
+        // The 20 GUSD fee owed by Carol belongs to the suppliers.
+        assert_eq!(contract.get_reserve().assets.0, 420);
+        assert_eq!(contract.get_insurance_fund().stablecoin.0, 40);
+        assert_eq!(contract.get_reserve_shares_value(U128(300)).0, 315);
+        assert_eq!(contract.get_reserve().exchange_rate.to_string(), "1.05");
+
+        // New supply gets fewer shares for the same GUSD.
+        assert_eq!(supply(&mut contract, "carol.near", 105), 100);
+
+        assert_eq!(contract.ft_total_supply().0, contract.total_debt);
+    }
+
+    #[test]
+    fn test_failed_mint_returns_gusd() {
+        let mut contract = setup();
+        fund_carol(&mut contract);
+        set_context("carol.near", 0);
+        contract.reserve_supply(U128(50));
+        set_token_result(PromiseResult::Failed);
+        let shares =
+            contract.resolve_reserve_supply("carol.near".parse().unwrap(), U128(50), U128(50));
+        assert_eq!(shares.0, 0);
+        assert_eq!(contract.ft_balance_of("carol.near".parse().unwrap()).0, 100);
+        assert_eq!(contract.get_reserve().total_shares.0, 400);
+        assert_eq!(contract.get_reserve().assets.0, 400);
+    }
+
+    #[test]
+    fn test_redeem_burns_gusdt() {
+        let mut contract = setup();
+        set_context("bob.near", 0);
+        contract.reserve_redeem(U128(40));
+        assert_eq!(
+            calls(),
+            [
+                ("gusdt.alice.near".to_string(), "ft_burn".to_string()),
+                (
+                    "alice.near".to_string(),
+                    "resolve_reserve_redeem".to_string()
+                )
+            ]
+        );
+        // Nothing is paid out before the gUSDT is burned.
+        assert_eq!(contract.get_reserve().assets.0, 400);
+
+        set_token_result(PromiseResult::Successful(vec![]));
+        let amount = contract.resolve_reserve_redeem("bob.near".parse().unwrap(), U128(40));
+        assert_eq!(amount.0, 40);
+        assert_eq!(contract.ft_balance_of("bob.near".parse().unwrap()).0, 40);
+        assert_eq!(contract.get_reserve().assets.0, 360);
+        assert_eq!(contract.get_reserve().total_shares.0, 360);
+    }
+
+    #[test]
+    #[should_panic(expected = "Burning the gUSDT failed")]
+    fn test_failed_burn_pays_nothing() {
+        let mut contract = setup();
+        set_context("bob.near", 0);
+        contract.reserve_redeem(U128(101));
+        set_token_result(PromiseResult::Failed);
+        contract.resolve_reserve_redeem("bob.near".parse().unwrap(), U128(101));
+    }
+
+    #[test]
+    fn test_supply_through_ft_transfer_call() {
+        let mut contract = setup();
+        fund_carol(&mut contract);
+        set_context("carol.near", 1);
+        contract.ft_transfer_call(
+            "alice.near".parse().unwrap(),
+            U128(50),
+            None,
+            "supply".to_string(),
+        );
+        assert_eq!(contract.ft_balance_of("carol.near".parse().unwrap()).0, 50);
+
+        // The protocol receives its own GUSD.
+        set_context("alice.near", 0);
+        contract.ft_on_transfer(
+            "carol.near".parse().unwrap(),
+            U128(50),
+            "supply".to_string(),
+        );
+        assert_eq!(
+            calls(),
+            [
+                ("gusdt.alice.near".to_string(), "ft_mint".to_string()),
+                (
+                    "alice.near".to_string(),
+                    "resolve_reserve_supply_transfer".to_string()
+                )
+            ]
+        );
+        assert_eq!(contract.get_reserve().total_shares.0, 450);
+        assert_eq!(contract.get_reserve().assets.0, 450);
+
+        set_token_result(PromiseResult::Successful(vec![]));
+        assert_eq!(
+            contract
+                .resolve_reserve_supply_transfer(U128(50), U128(50))
+                .0,
+            0
+        );
+
+        // A failed mint leaves the GUSD unused, for `ft_resolve_transfer` to refund.
+        set_context("alice.near", 0);
+        contract.ft_on_transfer(
+            "carol.near".parse().unwrap(),
+            U128(50),
+            "supply".to_string(),
+        );
+        set_token_result(PromiseResult::Failed);
+        assert_eq!(
+            contract
+                .resolve_reserve_supply_transfer(U128(50), U128(50))
+                .0,
+            50
+        );
+        assert_eq!(contract.get_reserve().total_shares.0, 450);
+        assert_eq!(contract.get_reserve().assets.0, 450);
+    }
+
+    #[test]
+    #[should_panic(expected = "GUSD is only accepted to supply the reserve")]
+    fn test_gusd_transfer_without_supply_rejected() {
+        let mut contract = setup();
+        set_context("alice.near", 0);
+        contract.ft_on_transfer("carol.near".parse().unwrap(), U128(50), "".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "The account carol.near is not registered with GUSD")]
+    fn test_redeem_needs_gusd_registration() {
+        let mut contract = setup();
+        set_context("carol.near", 0);
+        contract.reserve_redeem(U128(10));
+    }
+}
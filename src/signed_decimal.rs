@@ -0,0 +1,297 @@
+use crate::*;
+
+use near_sdk::serde::Serializer;
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// Signed companion of `BigDecimal` with the same 27 decimals, for values that may go negative
+/// such as deltas and PnL.
+///
+/// Stored as a sign and a `BigDecimal` magnitude. Zero is never negative. Rounding applies to
+/// the magnitude, so `Floor` and `Ceil` round towards and away from zero for negative values.
+#[derive(Copy, Clone, BorshSerialize, BorshDeserialize, Default)]
+pub struct SignedDecimal {
+    negative: bool,
+    abs: BigDecimal,
+}
+
+impl SignedDecimal {
+    fn new(negative: bool, abs: BigDecimal) -> Self {
+        Self {
+            negative: negative && abs != BigDecimal::zero(),
+            abs,
+        }
+    }
+
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    pub fn one() -> Self {
+        Self::from(BigDecimal::one())
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn abs(&self) -> BigDecimal {
+        self.abs
+    }
+
+    pub fn checked_add(&self, rhs: Self) -> Option<Self> {
+        if self.negative == rhs.negative {
+            return Some(Self::new(self.negative, self.abs.checked_add(rhs.abs)?));
+        }
+        Some(if self.abs >= rhs.abs {
+            Self::new(self.negative, self.abs - rhs.abs)
+        } else {
+            Self::new(rhs.negative, rhs.abs - self.abs)
+        })
+    }
+
+    pub fn checked_sub(&self, rhs: Self) -> Option<Self> {
+        self.checked_add(-rhs)
+    }
+
+    pub fn checked_mul(&self, rhs: Self) -> Option<Self> {
+        self.checked_mul_with(rhs, Rounding::HalfUp)
+    }
+
+    pub fn checked_mul_with(&self, rhs: Self, rounding: Rounding) -> Option<Self> {
+        Some(Self::new(
+            self.negative != rhs.negative,
+            self.abs.checked_mul_with(rhs.abs, rounding)?,
+        ))
+    }
+
+    /// `None` on overflow or division by zero.
+    pub fn checked_div(&self, rhs: Self) -> Option<Self> {
+        self.checked_div_with(rhs, Rounding::HalfUp)
+    }
+
+    pub fn checked_div_with(&self, rhs: Self, rounding: Rounding) -> Option<Self> {
+        Some(Self::new(
+            self.negative != rhs.negative,
+            self.abs.checked_div_with(rhs.abs, rounding)?,
+        ))
+    }
+
+    /// Rounds to an integer, `None` if it does not fit in an `i128`.
+    pub fn try_round_i128_with(&self, rounding: Rounding) -> Option<i128> {
+        let abs = self.abs.try_round_u128_with(rounding)?;
+        if self.negative {
+            0i128.checked_sub_unsigned(abs)
+        } else {
+            i128::try_from(abs).ok()
+        }
+    }
+
+    pub fn round_i128(&self) -> i128 {
+        self.try_round_i128_with(Rounding::HalfUp)
+            .expect("Value does not fit in i128")
+    }
+}
+
+impl From<BigDecimal> for SignedDecimal {
+    fn from(abs: BigDecimal) -> Self {
+        Self::new(false, abs)
+    }
+}
+
+impl From<i128> for SignedDecimal {
+    fn from(a: i128) -> Self {
+        Self::new(a < 0, BigDecimal::from(a.unsigned_abs()))
+    }
+}
+
+impl TryFrom<SignedDecimal> for BigDecimal {
+    type Error = String;
+
+    fn try_from(value: SignedDecimal) -> Result<Self, Self::Error> {
+        if value.negative {
+            return Err(format!("{} is negative", value));
+        }
+        Ok(value.abs)
+    }
+}
+
+impl Display for SignedDecimal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", self.abs)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl std::fmt::Debug for SignedDecimal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for SignedDecimal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('-') {
+            Some(abs) => Ok(Self::new(true, BigDecimal::from_str(abs)?)),
+            None => Ok(Self::from(BigDecimal::from_str(s)?)),
+        }
+    }
+}
+
+impl Serialize for SignedDecimal {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for SignedDecimal {
+    fn deserialize<D>(
+        deserializer: D,
+    ) -> Result<Self, <D as near_sdk::serde::Deserializer<'de>>::Error>
+    where
+        D: near_sdk::serde::Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        Self::from_str(&s).map_err(near_sdk::serde::de::Error::custom)
+    }
+}
+
+impl Neg for SignedDecimal {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        Self::new(!self.negative, self.abs)
+    }
+}
+
+impl Add for SignedDecimal {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.checked_add(rhs).expect("Addition overflow")
+    }
+}
+
+impl Sub for SignedDecimal {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.checked_sub(rhs).expect("Subtraction overflow")
+    }
+}
+
+impl Mul for SignedDecimal {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        self.checked_mul(rhs).expect("Multiplication overflow")
+    }
+}
+
+impl Div for SignedDecimal {
+    type Output = Self;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        self.checked_div(rhs)
+            .expect("Division overflow or division by zero")
+    }
+}
+
+impl PartialEq<Self> for SignedDecimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SignedDecimal {}
+
+impl PartialOrd for SignedDecimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SignedDecimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => self.abs.partial_cmp(&other.abs).unwrap(),
+            (true, true) => other.abs.partial_cmp(&self.abs).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(a: &str) -> SignedDecimal {
+        SignedDecimal::from_str(a).unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(s("1.5") - s("2"), s("-0.5"));
+        assert_eq!(s("-1.5") + s("2"), s("0.5"));
+        assert_eq!(s("-1.5") - s("2"), s("-3.5"));
+        assert_eq!(s("-1.5") * s("-2"), s("3"));
+        assert_eq!(s("3") / s("-2"), s("-1.5"));
+        assert_eq!(s("2") - s("2"), SignedDecimal::zero());
+        assert!(!(s("-2") + s("2")).is_negative());
+        assert_eq!(-SignedDecimal::zero(), SignedDecimal::zero());
+        assert_eq!(s("1").checked_div(SignedDecimal::zero()), None);
+    }
+
+    #[test]
+    fn test_ord() {
+        let mut values = vec![s("1"), s("-2"), s("0"), s("-0.5"), s("3")];
+        values.sort();
+        assert_eq!(values, vec![s("-2"), s("-0.5"), s("0"), s("1"), s("3")]);
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(SignedDecimal::from(-5i128), s("-5"));
+        assert_eq!(s("-2.5").round_i128(), -3);
+        assert_eq!(s("-2.5").try_round_i128_with(Rounding::Floor), Some(-2));
+        assert_eq!(s("2.5").try_round_i128_with(Rounding::HalfEven), Some(2));
+        assert_eq!(
+            SignedDecimal::from(BigDecimal::from(u128::MAX)).try_round_i128_with(Rounding::Floor),
+            None
+        );
+        assert_eq!(
+            (-SignedDecimal::from(BigDecimal::from(1u128 << 127))).round_i128(),
+            i128::MIN
+        );
+        assert_eq!(
+            BigDecimal::try_from(s("1.5")),
+            Ok(BigDecimal::from(3u32) / BigDecimal::from(2u32))
+        );
+        assert!(BigDecimal::try_from(s("-1.5")).is_err());
+    }
+
+    #[test]
+    fn test_encoding() {
+        let value = s("-12.345");
+        assert_eq!(value.to_string(), "-12.345");
+        let json = near_sdk::serde_json::to_string(&value).unwrap();
+        assert_eq!(json, "\"-12.345\"");
+        assert_eq!(
+            near_sdk::serde_json::from_str::<SignedDecimal>(&json).unwrap(),
+            value
+        );
+        let bytes = value.try_to_vec().unwrap();
+        assert_eq!(SignedDecimal::try_from_slice(&bytes).unwrap(), value);
+        assert!(SignedDecimal::from_str("--1").is_err());
+    }
+}
@@ -0,0 +1,522 @@
+//! Stateful fuzzing of `LendingProtocol`: random sequences of user actions on NEAR and token
+//! collateral, price updates and liquidations, with the protocol invariants checked after every
+//! step.
+//!
+//! Every call runs on a copy of the contract and is only kept if it does not panic, the way a
+//! failed transaction leaves the state untouched.
+
+use crate::*;
+
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::test_utils::{get_created_receipts, VMContextBuilder};
+use near_sdk::{mock::VmAction, testing_env};
+use proptest::prelude::*;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+const ACCOUNTS: [&str; 3] = ["alice.near", "bob.near", "carol.near"];
+const OWNER: &str = "gratis.near";
+/// Collateral is drawn in steps of `10^12` yocto NEAR, worth `1.5 * 10^10` at the default
+/// price, so that loans can also be large enough to close.
+const COLLATERAL_UNIT: Balance = 10u128.pow(12);
+/// Collateral token worth 2 per unit, borrowed against at 70% and liquidated past 80%.
+const TOKEN: &str = "wbtc.near";
+const TOKEN_PRICE: Price = Price {
+    multiplier: 2,
+    decimals: 0,
+};
+const TOKEN_COLLATERAL_FACTOR: u32 = 7000;
+const TOKEN_LIQUIDATION_THRESHOLD: u32 = 8000;
+
+#[derive(Debug, Clone)]
+enum Action {
+    Wait {
+        sec: u32,
+    },
+    /// Moves the NEAR price by `change` basis points.
+    MovePrice {
+        change: i32,
+    },
+    Deposit {
+        account: usize,
+        units: u128,
+    },
+    /// Borrows `percent` of what the loan can borrow, attaching `units` of collateral.
+    Borrow {
+        account: usize,
+        percent: u128,
+        units: u128,
+    },
+    Repay {
+        account: usize,
+        amount: u128,
+    },
+    /// Repays the whole debt and takes back the collateral.
+    Close {
+        account: usize,
+    },
+    StabilityPoolDeposit {
+        account: usize,
+        amount: u128,
+    },
+    RemoveCollateral {
+        account: usize,
+        units: u128,
+    },
+    Liquidate {
+        account: usize,
+        liquidator: usize,
+    },
+    ClaimCollateralSurplus {
+        account: usize,
+    },
+    /// Deposits `amount` of the collateral token through `ft_transfer_call`.
+    DepositToken {
+        account: usize,
+        amount: u128,
+    },
+    RemoveToken {
+        account: usize,
+        amount: u128,
+    },
+    /// Repays debt of `account` with `percent` of the GUSD of the liquidator, for its NEAR or
+    /// token collateral.
+    LiquidateWithRepay {
+        account: usize,
+        liquidator: usize,
+        token: bool,
+        percent: u128,
+    },
+}
+
+fn action() -> impl Strategy<Value = Action> {
+    let account = 0..ACCOUNTS.len();
+    let amount = 1..2 * 10u128.pow(12);
+    let token_amount = 1..10u128.pow(10);
+    prop_oneof![
+        2 => (0..7200u32).prop_map(|sec| Action::Wait { sec }),
+        // Drifting down so that loans become liquidatable.
+        3 => (-1900..1000i32).prop_map(|change| Action::MovePrice { change }),
+        2 => (account.clone(), 1..200u128)
+            .prop_map(|(account, units)| Action::Deposit { account, units }),
+        // Borrowing close to the limit, and past it, leaves loans near liquidation.
+        2 => (account.clone(), prop_oneof![1..=110u128, 90..=110u128], 0..20u128).prop_map(
+            |(account, percent, units)| Action::Borrow {
+                account,
+                percent,
+                units,
+            }
+        ),
+        1 => (account.clone(), amount.clone())
+            .prop_map(|(account, amount)| Action::Repay { account, amount }),
+        1 => account.clone().prop_map(|account| Action::Close { account }),
+        1 => (account.clone(), amount.clone())
+            .prop_map(|(account, amount)| { Action::StabilityPoolDeposit { account, amount } }),
+        1 => (account.clone(), 1..200u128)
+            .prop_map(|(account, units)| Action::RemoveCollateral { account, units }),
+        3 => (account.clone(), account.clone()).prop_map(|(account, liquidator)| {
+            Action::Liquidate {
+                account,
+                liquidator,
+            }
+        }),
+        1 => account.clone().prop_map(|account| Action::ClaimCollateralSurplus { account }),
+        2 => (account.clone(), token_amount.clone())
+            .prop_map(|(account, amount)| Action::DepositToken { account, amount }),
+        1 => (account.clone(), token_amount)
+            .prop_map(|(account, amount)| Action::RemoveToken { account, amount }),
+        3 => (account.clone(), account, any::<bool>(), 1..=100u128).prop_map(
+            |(account, liquidator, token, percent)| Action::LiquidateWithRepay {
+                account,
+                liquidator,
+                token,
+                percent,
+            }
+        ),
+    ]
+}
+
+struct Simulation {
+    contract: LendingProtocol,
+    /// NEAR held by the contract: attached deposits minus transfers out.
+    balance: Balance,
+    /// Collateral tokens held by the contract: tokens transferred in minus `ft_transfer`s out.
+    tokens: Balance,
+    timestamp: u64,
+}
+
+impl Simulation {
+    fn new() -> Self {
+        let mut simulation = Self {
+            contract: {
+                // GUSD balances live in storage, which the previous case left behind.
+                near_sdk::mock::with_mocked_blockchain(|b| b.take_storage());
+                set_context(OWNER, 0, 0);
+                let mut contract = LendingProtocol::new(vec![ACCOUNTS[0].parse().unwrap()]);
+                // Liquidating NEAR just past the 120% ratio keeps liquidations frequent.
+                contract.config.near_liquidation_threshold = 8400;
+                contract.config.collateral_assets.push(TOKEN.to_string());
+                contract.config.collateral_configs.insert(
+                    TOKEN.to_string(),
+                    CollateralConfig {
+                        collateral_factor: TOKEN_COLLATERAL_FACTOR,
+                        liquidation_threshold: TOKEN_LIQUIDATION_THRESHOLD,
+                        debt_ceiling: None,
+                    },
+                );
+                contract
+            },
+            balance: 0,
+            tokens: 0,
+            timestamp: 0,
+        };
+        simulation.set_price(15000);
+        // Borrowers pay for their GUSD storage first.
+        for account in ACCOUNTS {
+            let registered = simulation.call(account, ONE_NEAR / 100, |contract| {
+                contract.storage_deposit(None, None);
+            });
+            assert!(registered, "{} could not register with GUSD", account);
+        }
+        simulation
+    }
+
+    fn account_id(account: usize) -> AccountId {
+        ACCOUNTS[account].parse().unwrap()
+    }
+
+    /// Runs `f` as a call from `predecessor` with `deposit` attached and keeps its effects
+    /// unless it panics. Returns true if the call succeeded.
+    fn call(
+        &mut self,
+        predecessor: &str,
+        deposit: Balance,
+        f: impl FnOnce(&mut LendingProtocol),
+    ) -> bool {
+        set_context(predecessor, deposit, self.timestamp);
+        let mut contract =
+            LendingProtocol::try_from_slice(&self.contract.try_to_vec().unwrap()).unwrap();
+        if catch_unwind(AssertUnwindSafe(|| f(&mut contract))).is_err() {
+            return false;
+        }
+
+        let receipts = get_created_receipts();
+        let sent: Balance = receipts
+            .iter()
+            .flat_map(|receipt| &receipt.actions)
+            .map(|action| match action {
+                VmAction::Transfer { deposit } => *deposit,
+                VmAction::FunctionCall { deposit, .. } => *deposit,
+                _ => 0,
+            })
+            .sum();
+        self.balance = (self.balance + deposit)
+            .checked_sub(sent)
+            .expect("The contract sent more NEAR than it holds");
+        // Token transfers out always succeed here, their resolve callbacks are not run.
+        let tokens_sent: Balance = receipts
+            .iter()
+            .filter(|receipt| receipt.receiver_id.as_str() == TOKEN)
+            .flat_map(|receipt| &receipt.actions)
+            .map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "ft_transfer" => {
+                    let args: near_sdk::serde_json::Value =
+                        near_sdk::serde_json::from_slice(args).unwrap();
+                    args["amount"].as_str().unwrap().parse::<Balance>().unwrap()
+                }
+                _ => 0,
+            })
+            .sum();
+        self.tokens = self
+            .tokens
+            .checked_sub(tokens_sent)
+            .expect("The contract sent more tokens than it holds");
+        self.contract = contract;
+        true
+    }
+
+    fn set_price(&mut self, multiplier: u128) {
+        let mut data = PriceData {
+            timestamp: self.timestamp,
+            ..default_price_data()
+        };
+        data.prices[0].price = Some(Price {
+            multiplier,
+            decimals: 6,
+        });
+        data.prices.push(AssetOptionalPrice {
+            asset_id: TOKEN.to_string(),
+            price: Some(TOKEN_PRICE),
+        });
+        self.call(OWNER, 0, |contract| {
+            contract.apply_price_update(data);
+        });
+    }
+
+    fn debt(&self, account_id: &AccountId) -> u128 {
+        self.contract
+            .get_all_loans()
+            .get(account_id)
+            .map_or(0, |loan| loan.borrowed)
+    }
+
+    fn apply(&mut self, action: &Action) {
+        match *action {
+            Action::Wait { sec } => self.timestamp += to_nano(sec),
+            Action::MovePrice { change } => {
+                let multiplier = self.contract.near_price().multiplier as i128;
+                self.set_price((multiplier + multiplier * change as i128 / 10000) as u128);
+            }
+            Action::Deposit { account, units } => {
+                self.call(ACCOUNTS[account], units * COLLATERAL_UNIT, |contract| {
+                    contract.deposit_collateral();
+                });
+            }
+            Action::Borrow {
+                account,
+                percent,
+                units,
+            } => {
+                let account_id = Self::account_id(account);
+                let debt = self.debt(&account_id);
+                let amount = self.borrow_capacity(&account_id) * percent / 100 + 1;
+                let borrowed = self.call(ACCOUNTS[account], units * COLLATERAL_UNIT, |contract| {
+                    contract.borrow(amount)
+                });
+                if borrowed && self.debt(&account_id) > debt {
+                    self.assert_within_ratio(&account_id);
+                }
+            }
+            Action::Repay { account, amount } => {
+                self.call(ACCOUNTS[account], 0, |contract| {
+                    contract.repay(amount);
+                });
+            }
+            Action::Close { account } => {
+                self.call(ACCOUNTS[account], 0, |contract| contract.close_loan());
+            }
+            Action::StabilityPoolDeposit { account, amount } => {
+                self.call(ACCOUNTS[account], 0, |contract| {
+                    contract.stability_pool_deposit(U128(amount));
+                });
+            }
+            Action::RemoveCollateral { account, units } => {
+                self.call(ACCOUNTS[account], 0, |contract| {
+                    contract.remove_collateral(units * COLLATERAL_UNIT);
+                });
+            }
+            Action::Liquidate {
+                account,
+                liquidator,
+            } => {
+                self.call(ACCOUNTS[liquidator], 0, |contract| {
+                    contract.liquidate(Self::account_id(account))
+                });
+            }
+            Action::ClaimCollateralSurplus { account } => {
+                self.call(ACCOUNTS[account], 0, |contract| {
+                    contract.claim_collateral_surplus();
+                });
+            }
+            Action::DepositToken { account, amount } => {
+                let deposited = self.call(TOKEN, 0, |contract| {
+                    contract.ft_on_transfer(
+                        Self::account_id(account),
+                        U128(amount),
+                        "".to_string(),
+                    );
+                });
+                if deposited {
+                    self.tokens += amount;
+                }
+            }
+            Action::RemoveToken { account, amount } => {
+                self.call(ACCOUNTS[account], 0, |contract| {
+                    contract.remove_token_collateral(TOKEN.to_string(), U128(amount));
+                });
+            }
+            Action::LiquidateWithRepay {
+                account,
+                liquidator,
+                token,
+                percent,
+            } => {
+                let account_id = Self::account_id(account);
+                let liquidatable = self
+                    .contract
+                    .loans
+                    .contains_key(&account_id)
+                    .then(|| self.is_liquidatable(&account_id));
+                let asset_id = if token {
+                    TOKEN.to_string()
+                } else {
+                    self.contract.config.collateral_assets[0].clone()
+                };
+                let amount = self
+                    .contract
+                    .stablecoin_balance(&Self::account_id(liquidator))
+                    * percent
+                    / 100;
+                let liquidated = self.call(ACCOUNTS[liquidator], 0, |contract| {
+                    contract.liquidate_with_repay(account_id.clone(), asset_id, U128(amount));
+                });
+                assert!(
+                    !liquidated || liquidatable == Some(true),
+                    "Healthy loan of {} was liquidated with a repayment",
+                    account_id
+                );
+            }
+        }
+    }
+
+    /// Value of the token collateral of the loan weighted by the collateral factor, rounded
+    /// down.
+    fn token_borrowing_power(loan: &Loan) -> u128 {
+        let amount = loan
+            .token_collateral
+            .get(TOKEN)
+            .copied()
+            .unwrap_or_default();
+        amount * TOKEN_PRICE.multiplier * TOKEN_COLLATERAL_FACTOR as u128 / MAX_RATIO as u128
+    }
+
+    /// What the loan could borrow at its collateral ratio and the token collateral factor on
+    /// top of its debt.
+    fn borrow_capacity(&self, account_id: &AccountId) -> u128 {
+        set_context(OWNER, 0, self.timestamp);
+        let price = self.contract.conservative_near_price();
+        self.contract
+            .get_all_loans()
+            .get(account_id)
+            .map_or(0, |loan| {
+                (loan.collateral_value(&price) * 100 / loan.collateral_ratio
+                    + Self::token_borrowing_power(loan))
+                .saturating_sub(loan.borrowed)
+            })
+    }
+
+    fn assert_within_ratio(&self, account_id: &AccountId) {
+        set_context(OWNER, 0, self.timestamp);
+        let price = self.contract.conservative_near_price();
+        let loan = &self.contract.loans[account_id];
+        let ratio = if self.contract.lower_collateral_accounts.contains(account_id) {
+            LOWER_COLLATERAL_RATIO
+        } else {
+            MIN_COLLATERAL_RATIO
+        };
+        // borrowed <= collateral value * 100 / ratio + token borrowing power
+        let token_power = Self::token_borrowing_power(loan);
+        assert!(
+            U256::from(loan.collateral_value(&price)) * U256::from(100)
+                >= U256::from(loan.borrowed.saturating_sub(token_power)) * U256::from(ratio),
+            "{} borrowed beyond its collateral ratio",
+            account_id
+        );
+    }
+
+    fn is_liquidatable(&self, account_id: &AccountId) -> bool {
+        set_context(OWNER, 0, self.timestamp);
+        let loan = &self.contract.get_all_loans()[account_id];
+        self.contract
+            .is_loan_liquidatable(loan, &self.contract.collateral_prices(Valuation::Twap))
+    }
+
+    fn check_invariants(&mut self) {
+        // Redistributed debt and collateral are either in a loan or still in the pool.
+        let pool = self.contract.redistribution_pool;
+        let debt: u128 = self.contract.loans.values().map(|loan| loan.borrowed).sum();
+        assert_eq!(
+            debt + pool.debt,
+            self.contract.total_debt,
+            "Loans and the redistribution pool differ from the total debt"
+        );
+        let collateral: Balance = self.contract.loans.values().map(|l| l.collateral).sum();
+        assert_eq!(
+            collateral + pool.collateral,
+            self.contract.total_collateral,
+            "Loans and the redistribution pool differ from the total collateral"
+        );
+        // Loans can take their pending shares out of the pool.
+        let loans = self.contract.get_all_loans();
+        assert!(
+            loans.values().map(|loan| loan.borrowed).sum::<u128>() <= self.contract.total_debt,
+            "Loans owe more than the total debt"
+        );
+        assert!(
+            loans.values().map(|loan| loan.collateral).sum::<Balance>()
+                <= self.contract.total_collateral,
+            "Loans hold more than the total collateral"
+        );
+
+        assert_eq!(
+            self.contract.stablecoin.total_supply, self.contract.total_debt,
+            "GUSD supply differs from the total debt"
+        );
+
+        // Token collateral is never redistributed, it is all held by the loans.
+        let token_collateral: Balance = self
+            .contract
+            .loans
+            .values()
+            .filter_map(|loan| loan.token_collateral.get(TOKEN))
+            .sum();
+        let total_token_collateral = self
+            .contract
+            .total_token_collateral
+            .get(TOKEN)
+            .copied()
+            .unwrap_or_default();
+        assert_eq!(
+            token_collateral, total_token_collateral,
+            "Loans differ from the total token collateral"
+        );
+        assert_eq!(
+            self.tokens, total_token_collateral,
+            "Tokens held differ from the total token collateral"
+        );
+
+        let surplus: Balance = self.contract.collateral_surplus.values().sum();
+        assert!(
+            self.balance
+                >= self.contract.total_collateral + self.contract.insurance_fund.near + surplus,
+            "{} NEAR held for {} collateral, {} insurance and {} surplus",
+            self.balance,
+            self.contract.total_collateral,
+            self.contract.insurance_fund.near,
+            surplus
+        );
+
+        for account_id in loans.keys() {
+            if self.is_liquidatable(account_id) {
+                continue;
+            }
+            let liquidated = self.call(OWNER, 0, |contract| contract.liquidate(account_id.clone()));
+            assert!(!liquidated, "Healthy loan of {} was liquidated", account_id);
+        }
+    }
+}
+
+fn set_context(predecessor: &str, deposit: Balance, timestamp: u64) {
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(OWNER.parse().unwrap())
+        .predecessor_account_id(predecessor.parse().unwrap())
+        .attached_deposit(deposit)
+        .block_timestamp(timestamp)
+        .build());
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn invariants_hold(actions in proptest::collection::vec(action(), 1..100)) {
+        let mut simulation = Simulation::new();
+        for action in &actions {
+            simulation.apply(action);
+            simulation.check_invariants();
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use crate::*;
+
+/// Factor the running product is scaled up by once it drops below its inverse, as in Liquity.
+const SCALE_FACTOR: u128 = 1_000_000_000;
+
+/// Pool of GUSD that absorbs the debt of liquidated loans in exchange for their NEAR collateral.
+///
+/// Uses product/sum snapshot accounting: a deposit made when the running product was `P_0` and
+/// the running sum was `S_0` is worth `initial * P / P_0` GUSD and has earned
+/// `initial * (S - S_0) / P_0` NEAR. When a liquidation empties the pool the epoch is bumped,
+/// which zeroes every older deposit while keeping the collateral they earned claimable.
+///
+/// To keep the product from rounding down to zero it is multiplied by `SCALE_FACTOR` whenever it
+/// drops below `1 / SCALE_FACTOR`, which starts a new scale with its own running sum. A deposit
+/// made a scale ago is worth `SCALE_FACTOR` times less than the product says, older ones round
+/// to zero.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StabilityPool {
+    pub deposits: HashMap<AccountId, PoolDeposit>,
+    /// GUSD held by the pool, net of the debt it has absorbed.
+    pub total_deposits: Balance,
+    /// NEAR seized from liquidations that depositors have not claimed yet.
+    pub total_collateral: Balance,
+    pub product: BigDecimal,
+    /// Running sum for every scale of every epoch, the last entries are the current ones.
+    pub epoch_sums: Vec<Vec<BigDecimal>>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolDeposit {
+    pub initial: Balance,
+    pub snapshot_product: BigDecimal,
+    pub snapshot_sum: BigDecimal,
+    pub snapshot_epoch: u64,
+    pub snapshot_scale: u64,
+    /// Collateral gain settled at the last checkpoint but not yet claimed.
+    pub collateral_gain: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StabilityPoolDepositView {
+    pub deposit: U128,
+    pub collateral_gain: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StabilityPoolView {
+    pub total_deposits: U128,
+    pub total_collateral: U128,
+    pub depositors: u64,
+}
+
+impl Default for StabilityPool {
+    fn default() -> Self {
+        Self {
+            deposits: HashMap::new(),
+            total_deposits: 0,
+            total_collateral: 0,
+            product: BigDecimal::one(),
+            epoch_sums: vec![vec![BigDecimal::zero()]],
+        }
+    }
+}
+
+impl StabilityPool {
+    fn current_epoch(&self) -> u64 {
+        (self.epoch_sums.len() - 1) as u64
+    }
+
+    fn current_scale(&self) -> u64 {
+        (self.epoch_sums.last().unwrap().len() - 1) as u64
+    }
+
+    fn current_sum(&self) -> BigDecimal {
+        *self.epoch_sums.last().unwrap().last().unwrap()
+    }
+
+    pub fn compounded_deposit(&self, deposit: &PoolDeposit) -> Balance {
+        if deposit.snapshot_epoch != self.current_epoch() {
+            return 0;
+        }
+        let compounded = BigDecimal::from(deposit.initial)
+            .mul_with(self.product, Rounding::Floor)
+            .div_with(deposit.snapshot_product, Rounding::Floor);
+        match self.current_scale() - deposit.snapshot_scale {
+            0 => compounded.floor_u128(),
+            1 => compounded
+                .div_with(BigDecimal::from(SCALE_FACTOR), Rounding::Floor)
+                .floor_u128(),
+            _ => 0,
+        }
+    }
+
+    pub fn collateral_gain(&self, deposit: &PoolDeposit) -> Balance {
+        let sums = &self.epoch_sums[deposit.snapshot_epoch as usize];
+        let scale = deposit.snapshot_scale as usize;
+        // Gains of the next scale were summed up at a product `SCALE_FACTOR` times larger.
+        let next_scale = sums.get(scale + 1).map_or(BigDecimal::zero(), |sum| {
+            sum.div_with(BigDecimal::from(SCALE_FACTOR), Rounding::Floor)
+        });
+        let earned = BigDecimal::from(deposit.initial)
+            .mul_with(
+                sums[scale] - deposit.snapshot_sum + next_scale,
+                Rounding::Floor,
+            )
+            .div_with(deposit.snapshot_product, Rounding::Floor)
+            .floor_u128();
+        deposit.collateral_gain + earned
+    }
+
+    /// Settles the compounded deposit and collateral gain of the account and restarts its
+    /// snapshots from the current product and sum.
+    fn checkpoint(&mut self, account_id: &AccountId) -> &mut PoolDeposit {
+        let (initial, collateral_gain) = match self.deposits.get(account_id) {
+            Some(deposit) => (
+                self.compounded_deposit(deposit),
+                self.collateral_gain(deposit),
+            ),
+            None => (0, 0),
+        };
+        let snapshot = PoolDeposit {
+            initial,
+            snapshot_product: self.product,
+            snapshot_sum: self.current_sum(),
+            snapshot_epoch: self.current_epoch(),
+            snapshot_scale: self.current_scale(),
+            collateral_gain,
+        };
+        let deposit = self
+            .deposits
+            .entry(account_id.clone())
+            .or_insert(snapshot.clone());
+        *deposit = snapshot;
+        deposit
+    }
+
+    fn cleanup(&mut self, account_id: &AccountId) {
+        if let Some(deposit) = self.deposits.get(account_id) {
+            if deposit.initial == 0 && deposit.collateral_gain == 0 {
+                self.deposits.remove(account_id);
+            }
+        }
+    }
+
+    pub fn deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        assert!(amount > 0, "Deposit Amount should be greater than 0");
+        self.checkpoint(account_id).initial += amount;
+        self.total_deposits += amount;
+    }
+
+    /// Withdraws up to `amount` of the compounded deposit, or all of it when `amount` is `None`.
+    pub fn withdraw(&mut self, account_id: &AccountId, amount: Option<Balance>) -> Balance {
+        assert!(
+            self.deposits.contains_key(account_id),
+            "No stability pool deposit"
+        );
+        let deposit = self.checkpoint(account_id);
+        let amount = amount.unwrap_or(deposit.initial);
+        assert!(
+            amount <= deposit.initial,
+            "Withdraw Amount should be less than the deposited amount"
+        );
+        deposit.initial -= amount;
+        // Rounding of compounded deposits may leave the pool a few units short.
+        self.total_deposits = self.total_deposits.saturating_sub(amount);
+        self.cleanup(account_id);
+        amount
+    }
+
+    pub fn take_collateral_gain(&mut self, account_id: &AccountId) -> Balance {
+        assert!(
+            self.deposits.contains_key(account_id),
+            "No stability pool deposit"
+        );
+        let deposit = self.checkpoint(account_id);
+        let gain = std::mem::take(&mut deposit.collateral_gain);
+        let gain = std::cmp::min(gain, self.total_collateral);
+        self.total_collateral -= gain;
+        self.cleanup(account_id);
+        gain
+    }
+
+    /// Cancels `debt` against the pool deposits and distributes `collateral` to depositors
+    /// pro-rata to their compounded deposits.
+    pub fn offset(&mut self, debt: Balance, collateral: Balance) {
+        assert!(
+            debt > 0 && debt <= self.total_deposits,
+            "Stability pool cannot absorb the debt"
+        );
+        let total = BigDecimal::from(self.total_deposits);
+        let sum = self.epoch_sums.last_mut().unwrap().last_mut().unwrap();
+        // Gains and remaining deposits round down so the pool never pays out more than it holds.
+        *sum = *sum
+            + BigDecimal::from(collateral)
+                .mul_with(self.product, Rounding::Floor)
+                .div_with(total, Rounding::Floor);
+        self.total_collateral += collateral;
+
+        let remaining = self.total_deposits - debt;
+        self.total_deposits = remaining;
+        let product_with = |scale: u128| {
+            self.product
+                .mul_with(BigDecimal::from(scale), Rounding::Floor)
+                .mul_with(BigDecimal::from(remaining), Rounding::Floor)
+                .div_with(total, Rounding::Floor)
+        };
+        let product = product_with(1);
+        let min_product = BigDecimal::one().div_u128(SCALE_FACTOR);
+        if product >= min_product {
+            self.product = product;
+            return;
+        }
+        let product = product_with(SCALE_FACTOR);
+        if product > BigDecimal::zero() {
+            self.epoch_sums.last_mut().unwrap().push(BigDecimal::zero());
+            self.product = product;
+        } else {
+            // The pool is emptied, or what is left of it is too small to tell apart from zero.
+            // Older deposits are worth nothing, a rest is kept by the pool for new deposits.
+            self.epoch_sums.push(vec![BigDecimal::zero()]);
+            self.product = BigDecimal::one();
+        }
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    /// Deposits GUSD of the caller into the pool.
+    pub fn stability_pool_deposit(&mut self, amount: U128) {
+        let account_id = env::predecessor_account_id();
+        self.take_stablecoin(&account_id, amount.0, "Stability pool deposit");
+        self.stability_pool.deposit(&account_id, amount.0);
+    }
+
+    /// Withdraws GUSD of the compounded deposit of the caller, all of it when `amount` is `None`.
+    pub fn stability_pool_withdraw(&mut self, amount: Option<U128>) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let amount = self
+            .stability_pool
+            .withdraw(&account_id, amount.map(|a| a.0));
+        assert!(amount > 0, "Withdraw Amount should be greater than 0");
+        self.give_stablecoin(&account_id, amount, "Stability pool withdrawal");
+        U128(amount)
+    }
+
+    pub fn stability_pool_claim_gains(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let gain = self.stability_pool.take_collateral_gain(&account_id);
+        assert!(gain > 0, "No collateral gain to claim");
+        Promise::new(account_id).transfer(gain)
+    }
+
+    pub fn get_stability_pool_deposit(
+        &self,
+        account_id: AccountId,
+    ) -> Option<StabilityPoolDepositView> {
+        self.stability_pool
+            .deposits
+            .get(&account_id)
+            .map(|deposit| StabilityPoolDepositView {
+                deposit: U128(self.stability_pool.compounded_deposit(deposit)),
+                collateral_gain: U128(self.stability_pool.collateral_gain(deposit)),
+            })
+    }
+
+    pub fn get_stability_pool(&self) -> StabilityPoolView {
+        StabilityPoolView {
+            total_deposits: U128(self.stability_pool.total_deposits),
+            total_collateral: U128(self.stability_pool.total_collateral),
+            depositors: self.stability_pool.deposits.len() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(id: &str) -> AccountId {
+        id.parse().unwrap()
+    }
+
+    #[test]
+    fn test_offset_pro_rata() {
+        let alice = account("alice.near");
+        let bob = account("bob.near");
+        let mut pool = StabilityPool::default();
+        pool.deposit(&alice, 300);
+        pool.deposit(&bob, 100);
+
+        pool.offset(200, 4000);
+
+        let a = pool.deposits.get(&alice).unwrap();
+        let b = pool.deposits.get(&bob).unwrap();
+        assert_eq!(pool.compounded_deposit(a), 150);
+        assert_eq!(pool.compounded_deposit(b), 50);
+        assert_eq!(pool.collateral_gain(a), 3000);
+        assert_eq!(pool.collateral_gain(b), 1000);
+        assert_eq!(pool.total_deposits, 200);
+    }
+
+    #[test]
+    fn test_deposit_after_offset() {
+        let alice = account("alice.near");
+        let bob = account("bob.near");
+        let mut pool = StabilityPool::default();
+        pool.deposit(&alice, 100);
+        pool.offset(50, 1000);
+        pool.deposit(&bob, 50);
+        pool.offset(50, 1000);
+
+        let a = pool.deposits.get(&alice).unwrap();
+        let b = pool.deposits.get(&bob).unwrap();
+        assert_eq!(pool.compounded_deposit(a), 25);
+        assert_eq!(pool.compounded_deposit(b), 25);
+        assert_eq!(pool.collateral_gain(a), 1500);
+        assert_eq!(pool.collateral_gain(b), 500);
+    }
+
+    #[test]
+    fn test_pool_emptied_starts_new_epoch() {
+        let alice = account("alice.near");
+        let bob = account("bob.near");
+        let mut pool = StabilityPool::default();
+        pool.deposit(&alice, 100);
+        pool.offset(100, 1000);
+        pool.deposit(&bob, 10);
+
+        assert_eq!(pool.withdraw(&alice, None), 0);
+        assert_eq!(pool.take_collateral_gain(&alice), 1000);
+        assert!(!pool.deposits.contains_key(&alice));
+
+        let b = pool.deposits.get(&bob).unwrap();
+        assert_eq!(pool.compounded_deposit(b), 10);
+        assert_eq!(pool.collateral_gain(b), 0);
+    }
+
+    #[test]
+    fn test_product_is_rescaled() {
+        let alice = account("alice.near");
+        let bob = account("bob.near");
+        let mut pool = StabilityPool::default();
+        pool.deposit(&alice, 1_000_000);
+        // Each offset leaves a millionth of the pool, the product would reach 10^-30.
+        for _ in 0..5 {
+            pool.offset(999_999, 1000);
+            pool.deposit(&alice, 999_999);
+        }
+        assert_eq!(pool.current_scale(), 3);
+        assert_eq!(pool.withdraw(&alice, None), 1_000_000);
+        assert_eq!(pool.take_collateral_gain(&alice), 5000);
+
+        // New deposits keep their value and earn gains.
+        pool.deposit(&bob, 999_999);
+        pool.offset(500_000, 1000);
+        let b = pool.deposits.get(&bob).unwrap();
+        assert_eq!(pool.compounded_deposit(b), 499_998);
+        assert_eq!(pool.collateral_gain(b), 999);
+    }
+
+    #[test]
+    fn test_gain_across_scales() {
+        let alice = account("alice.near");
+        let mut pool = StabilityPool::default();
+        pool.deposit(&alice, 10u128.pow(12));
+        pool.offset(10u128.pow(12) - 100, 1000);
+        assert_eq!(pool.current_scale(), 1);
+        pool.offset(50, 1000);
+
+        let a = pool.deposits.get(&alice).unwrap();
+        assert_eq!(pool.compounded_deposit(a), 50);
+        assert_eq!(pool.collateral_gain(a), 2000);
+    }
+}
@@ -0,0 +1,215 @@
+//! GUSD, the Gratis stablecoin: a NEP-141 token implemented by the protocol contract itself.
+//! Borrowing mints it against collateral and repaying burns it, so its total supply always equals
+//! the total debt. The protocol account holds the GUSD of the stability pool, the reserve and the
+//! insurance fund.
+
+use crate::*;
+
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
+
+/// GUSD has the decimals of the USD prices the protocol values collateral at.
+pub const STABLECOIN_DECIMALS: u8 = 6;
+
+impl LendingProtocol {
+    /// The borrowed asset, GUSD, identified by the protocol account.
+    pub(crate) fn borrow_asset(&self) -> AssetId {
+        env::current_account_id().to_string()
+    }
+
+    pub(crate) fn stablecoin_balance(&self, account_id: &AccountId) -> Balance {
+        self.stablecoin.accounts.get(account_id).unwrap_or_default()
+    }
+
+    /// Accounts pay for their GUSD storage with `storage_deposit` before receiving any.
+    pub(crate) fn assert_stablecoin_registered(&self, account_id: &AccountId) {
+        assert!(
+            self.stablecoin.accounts.contains_key(account_id),
+            "The account {} is not registered with GUSD",
+            account_id
+        );
+    }
+
+    pub(crate) fn mint_stablecoin(&mut self, account_id: &AccountId, amount: Balance, memo: &str) {
+        self.assert_stablecoin_registered(account_id);
+        self.stablecoin.internal_deposit(account_id, amount);
+        FtMint {
+            owner_id: account_id,
+            amount: &U128(amount),
+            memo: Some(memo),
+        }
+        .emit();
+    }
+
+    pub(crate) fn burn_stablecoin(&mut self, account_id: &AccountId, amount: Balance, memo: &str) {
+        if amount == 0 {
+            return;
+        }
+        assert!(
+            self.stablecoin_balance(account_id) >= amount,
+            "Not enough GUSD"
+        );
+        self.stablecoin.internal_withdraw(account_id, amount);
+        FtBurn {
+            owner_id: account_id,
+            amount: &U128(amount),
+            memo: Some(memo),
+        }
+        .emit();
+    }
+
+    /// Moves GUSD of an account into the protocol account.
+    pub(crate) fn take_stablecoin(&mut self, account_id: &AccountId, amount: Balance, memo: &str) {
+        assert!(amount > 0, "Deposit Amount should be greater than 0");
+        assert!(
+            self.stablecoin_balance(account_id) >= amount,
+            "Not enough GUSD"
+        );
+        self.stablecoin.internal_transfer(
+            account_id,
+            &env::current_account_id(),
+            amount,
+            Some(memo.to_string()),
+        );
+    }
+
+    /// Moves GUSD of the protocol account to an account.
+    pub(crate) fn give_stablecoin(&mut self, account_id: &AccountId, amount: Balance, memo: &str) {
+        self.assert_stablecoin_registered(account_id);
+        self.stablecoin.internal_transfer(
+            &env::current_account_id(),
+            account_id,
+            amount,
+            Some(memo.to_string()),
+        );
+    }
+
+    /// Hook of the token for GUSD that would leave the supply without repaying debt: the balance
+    /// of an account closed with `storage_unregister(Some(true))` and the refund of a
+    /// `ft_transfer_call` whose sender closed the account meanwhile. The total supply has to stay
+    /// the total debt, so it panics.
+    fn reject_stablecoin_burn(&self, account_id: AccountId, amount: Balance) {
+        assert!(
+            amount == 0,
+            "{} GUSD of {} can only be burned by repaying debt",
+            amount,
+            account_id
+        );
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(
+    LendingProtocol,
+    stablecoin,
+    reject_stablecoin_burn
+);
+near_contract_standards::impl_fungible_token_storage!(
+    LendingProtocol,
+    stablecoin,
+    reject_stablecoin_burn
+);
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for LendingProtocol {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "Gratis USD".to_string(),
+            symbol: "GUSD".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: STABLECOIN_DECIMALS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+
+    /// Bob borrows 200 GUSD with a 10% borrow fee.
+    fn setup() -> LendingProtocol {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        contract.config.borrow_fee = 1000;
+        register(&mut contract, "bob.near");
+        register(&mut contract, "carol.near");
+        set_context("bob.near", 100000);
+        contract.deposit_collateral();
+        set_context("bob.near", 0);
+        contract.borrow(200);
+        contract
+    }
+
+    #[test]
+    fn test_borrow_mints_and_repay_burns() {
+        let mut contract = setup();
+        assert_eq!(contract.ft_metadata().symbol, "GUSD");
+        assert_eq!(contract.ft_balance_of("bob.near".parse().unwrap()).0, 200);
+        // The fee is minted to the insurance fund.
+        assert_eq!(contract.get_insurance_fund().stablecoin.0, 20);
+        assert_eq!(contract.ft_total_supply().0, 220);
+        assert_eq!(contract.total_debt, 220);
+
+        contract.repay(50);
+        assert_eq!(contract.ft_balance_of("bob.near".parse().unwrap()).0, 150);
+        assert_eq!(contract.ft_total_supply().0, 170);
+        assert_eq!(contract.total_debt, 170);
+    }
+
+    #[test]
+    fn test_close_loan() {
+        let mut contract = setup();
+        // Bob owes 20 GUSD more than he borrowed and gets them from Carol.
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        set_context("carol.near", 0);
+        contract.borrow(100);
+        set_context("carol.near", 1);
+        contract.ft_transfer("bob.near".parse().unwrap(), U128(20), None);
+
+        set_context("bob.near", 0);
+        contract.close_loan();
+        assert!(!contract.loans.contains_key(&"bob.near".parse().unwrap()));
+        assert_eq!(contract.ft_balance_of("bob.near".parse().unwrap()).0, 0);
+        assert_eq!(contract.ft_total_supply().0, 110);
+        assert_eq!(contract.total_debt, 110);
+    }
+
+    #[test]
+    #[should_panic(expected = "The account carol.near is not registered with GUSD")]
+    fn test_borrow_needs_registration() {
+        set_context("alice.near", 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        set_context("carol.near", 100000);
+        contract.deposit_collateral();
+        set_context("carol.near", 0);
+        contract.borrow(100);
+    }
+
+    #[test]
+    #[should_panic(expected = "200 GUSD of bob.near can only be burned by repaying debt")]
+    fn test_force_unregister_rejected() {
+        let mut contract = setup();
+        set_context("bob.near", 1);
+        contract.storage_unregister(Some(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough GUSD")]
+    fn test_repay_needs_gusd() {
+        let mut contract = setup();
+        set_context("bob.near", 1);
+        contract.ft_transfer("carol.near".parse().unwrap(), U128(150), None);
+
+        set_context("bob.near", 0);
+        contract.repay(100);
+    }
+}
@@ -0,0 +1,42 @@
+//! Helpers shared by the unit tests.
+
+use crate::*;
+
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::{test_utils::VMContextBuilder, testing_env};
+
+/// Mocks a call from `predecessor` with `amount` attached.
+pub fn set_context(predecessor: &str, amount: Balance) {
+    set_context_at(predecessor, amount, 0);
+}
+
+/// Mocks a call from `predecessor` with `amount` attached, made at `timestamp_sec`.
+pub fn set_context_at(predecessor: &str, amount: Balance, timestamp_sec: u32) {
+    let mut builder = VMContextBuilder::new();
+    builder.predecessor_account_id(predecessor.parse().unwrap());
+    builder.attached_deposit(amount);
+    builder.block_timestamp(to_nano(timestamp_sec));
+
+    testing_env!(builder.build());
+}
+
+/// Registers the account with GUSD, which it needs before borrowing or receiving any.
+pub fn register(contract: &mut LendingProtocol, account_id: &str) {
+    set_context(account_id, ONE_NEAR / 100);
+    contract.storage_deposit(None, None);
+}
+
+/// Price data with a NEAR price of `multiplier` at 6 decimals.
+pub fn near_price_data(multiplier: u128) -> PriceData {
+    let mut data = default_price_data();
+    data.prices[0].price = Some(Price {
+        multiplier,
+        decimals: 6,
+    });
+    data
+}
+
+/// Makes a NEAR price of `multiplier` the latest price, without going through the oracles.
+pub fn set_near_price(contract: &mut LendingProtocol, multiplier: u128) {
+    contract.price_data = Some(near_price_data(multiplier));
+}
@@ -0,0 +1,238 @@
+use crate::*;
+
+use near_sdk::Timestamp;
+
+/// Number of snapshots kept per asset. Older ones are overwritten.
+pub const PRICE_HISTORY_SIZE: usize = 64;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceSnapshot {
+    pub price: Price,
+    #[serde(with = "u64_dec_format")]
+    pub timestamp: Timestamp,
+}
+
+/// Ring buffer of the prices stored for an asset.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceHistory {
+    pub snapshots: Vec<PriceSnapshot>,
+    /// Slot the next snapshot is written to once the buffer is full.
+    pub next: u32,
+}
+
+impl PriceHistory {
+    pub fn push(&mut self, snapshot: PriceSnapshot) {
+        if self.snapshots.len() < PRICE_HISTORY_SIZE {
+            self.snapshots.push(snapshot);
+        } else {
+            self.snapshots[self.next as usize] = snapshot;
+            self.next = ((self.next as usize + 1) % PRICE_HISTORY_SIZE) as u32;
+        }
+    }
+
+    /// Snapshots from the oldest to the latest.
+    pub fn chronological(&self) -> Vec<PriceSnapshot> {
+        let (newer, older) = self.snapshots.split_at(self.next as usize);
+        older.iter().chain(newer).copied().collect()
+    }
+
+    /// Average of the prices over the `window` before `now`, each price weighted by how long it
+    /// was the latest one. The oldest snapshot is assumed to cover the window from its start,
+    /// even if it was taken later, up to its successor. Returns `None` without snapshots.
+    pub fn twap(&self, now: Timestamp, window: Timestamp) -> Option<Price> {
+        let snapshots = self.chronological();
+        let latest = snapshots.last()?;
+        let decimals = snapshots.iter().map(|s| s.price.decimals).max().unwrap();
+        let start = now.saturating_sub(window);
+
+        let mut sum = U384::zero();
+        let mut total = 0u64;
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let from = if i == 0 {
+                start
+            } else {
+                std::cmp::max(snapshot.timestamp, start)
+            };
+            let to = snapshots.get(i + 1).map_or(now, |next| next.timestamp);
+            if to <= from {
+                continue;
+            }
+            sum += snapshot.price.normalized_multiplier(decimals) * U384::from(to - from);
+            total += to - from;
+        }
+        if total == 0 {
+            return Some(latest.price);
+        }
+
+        Some(Price {
+            multiplier: (sum / U384::from(total)).as_u128(),
+            decimals,
+        })
+    }
+}
+
+impl LendingProtocol {
+    /// Appends the prices of a stored `PriceData` to the history of their assets.
+    pub(crate) fn record_price_data(&mut self, data: &PriceData) {
+        let timestamp = env::block_timestamp();
+        for AssetOptionalPrice { asset_id, price } in &data.prices {
+            if let Some(price) = price {
+                self.price_history
+                    .entry(asset_id.clone())
+                    .or_default()
+                    .push(PriceSnapshot {
+                        price: *price,
+                        timestamp,
+                    });
+            }
+        }
+    }
+
+    /// NEAR TWAP over the configured window, the spot price while there is no history.
+    pub(crate) fn near_twap(&self) -> Price {
+        let asset_id = &self.get_latest_price().prices[0].asset_id;
+        self.price_history
+            .get(asset_id)
+            .and_then(|history| {
+                history.twap(env::block_timestamp(), to_nano(self.config.twap_window_sec))
+            })
+            .unwrap_or_else(|| self.near_price())
+    }
+
+    /// The lower of the NEAR spot price and TWAP, used to value collateral a user takes risk
+    /// against.
+    pub(crate) fn conservative_near_price(&self) -> Price {
+        let spot = self.near_price();
+        let twap = self.near_twap();
+        std::cmp::min(spot, twap)
+    }
+}
+
+#[near_bindgen]
+impl LendingProtocol {
+    pub fn get_twap(&self, asset_id: AssetId) -> Option<Price> {
+        self.price_history.get(&asset_id).and_then(|history| {
+            history.twap(env::block_timestamp(), to_nano(self.config.twap_window_sec))
+        })
+    }
+
+    pub fn get_price_history(&self, asset_id: AssetId) -> Vec<PriceSnapshot> {
+        self.price_history
+            .get(&asset_id)
+            .map(|history| history.chronological())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::*;
+
+    fn push_near_price(contract: &mut LendingProtocol, multiplier: u128, timestamp_sec: u32) {
+        set_context_at("alice.near", 0, timestamp_sec);
+        let data = near_price_data(multiplier);
+        contract.record_price_data(&data);
+        contract.price_data = Some(data);
+    }
+
+    fn snapshot(multiplier: u128, timestamp_sec: u32) -> PriceSnapshot {
+        PriceSnapshot {
+            price: Price {
+                multiplier,
+                decimals: 6,
+            },
+            timestamp: to_nano(timestamp_sec),
+        }
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut history = PriceHistory::default();
+        for i in 0..PRICE_HISTORY_SIZE as u32 + 3 {
+            history.push(snapshot(i as u128 + 1, i));
+        }
+        let snapshots = history.chronological();
+        assert_eq!(snapshots.len(), PRICE_HISTORY_SIZE);
+        assert_eq!(snapshots[0].timestamp, to_nano(3));
+        assert_eq!(
+            snapshots.last().unwrap().timestamp,
+            to_nano(PRICE_HISTORY_SIZE as u32 + 2)
+        );
+    }
+
+    #[test]
+    fn test_time_weighted_average() {
+        let mut history = PriceHistory::default();
+        history.push(snapshot(10000, 0));
+        history.push(snapshot(20000, 900));
+        history.push(snapshot(16000, 1500));
+
+        // Over the last 1500 seconds: 10000 for 300s, 20000 for 600s, 16000 for 600s.
+        let twap = history.twap(to_nano(2100), to_nano(1500)).unwrap();
+        assert_eq!(twap.multiplier, 16400);
+        assert!(PriceHistory::default().twap(0, 0).is_none());
+    }
+
+    #[test]
+    fn test_window_before_oldest_snapshot() {
+        let mut history = PriceHistory::default();
+        history.push(snapshot(10000, 1000));
+        history.push(snapshot(20000, 1600));
+
+        // The window starts at 500: 10000 from then for 1100s, 20000 for 400s.
+        let twap = history.twap(to_nano(2000), to_nano(1500)).unwrap();
+        assert_eq!(twap.multiplier, 12666);
+    }
+
+    #[test]
+    fn test_collateral_checks_use_conservative_price() {
+        set_context_at("alice.near", 0, 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        push_near_price(&mut contract, 15000, 0);
+        register(&mut contract, "bob.near");
+
+        set_context_at("carol.near", 40000, 0);
+        contract.deposit_collateral();
+        set_context_at("bob.near", 10000, 0);
+        contract.deposit_collateral();
+
+        // A spike of the spot price does not raise the borrowing limit until it holds.
+        push_near_price(&mut contract, 30000, 1790);
+        set_context_at("bob.near", 0, 1800);
+        assert_eq!(contract.near_twap().multiplier, 15083);
+        contract.borrow(200);
+        assert_eq!(contract.loans[&"bob.near".parse().unwrap()].borrowed, 0);
+        contract.borrow(125);
+        assert_eq!(contract.loans[&"bob.near".parse().unwrap()].borrowed, 125);
+    }
+
+    #[test]
+    #[should_panic(expected = "Loan is not liquidatable")]
+    fn test_liquidation_uses_twap() {
+        set_context_at("alice.near", 0, 0);
+        let mut contract = LendingProtocol::new(vec![]);
+        contract.config.deposit_fee = 0;
+        push_near_price(&mut contract, 15000, 0);
+        register(&mut contract, "bob.near");
+        register(&mut contract, "carol.near");
+
+        set_context_at("carol.near", 20000, 0);
+        contract.deposit_collateral();
+        set_context_at("carol.near", 0, 0);
+        contract.borrow(100);
+        set_context_at("bob.near", 10000, 0);
+        contract.deposit_collateral();
+        set_context_at("bob.near", 0, 0);
+        contract.borrow(120);
+
+        // A short dip of the spot price below the liquidation threshold is ignored.
+        push_near_price(&mut contract, 5000, 1790);
+        set_context_at("alice.near", 0, 1800);
+        contract.liquidate("bob.near".parse().unwrap());
+    }
+}
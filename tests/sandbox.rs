@@ -0,0 +1,700 @@
+//! End-to-end tests against a local NEAR sandbox, with the oracle contract from `oracle/`
+//! standing in for `priceoracle.testnet` and the mock token of `mocks/ft` for the collateral
+//! tokens.
+//!
+//! They need the sandbox node, which the `near-workspaces` build script downloads (or set
+//! `NEAR_SANDBOX_BIN_PATH`), and are ignored by default:
+//!
+//! ```sh
+//! cargo test --test sandbox -- --ignored
+//! ```
+
+use near_workspaces::network::Sandbox;
+use near_workspaces::types::NearToken;
+use near_workspaces::{Account, Contract, Worker};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+const ONE_GUSD: u128 = 1_000_000;
+/// Reports older than this are left out of the oracle prices.
+const RECENCY_DURATION_SEC: u32 = 60;
+
+/// Builds the workspace to wasm the same way `build.sh` does and returns the contract code.
+fn wasm(name: &str) -> Vec<u8> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let status = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .args([
+            "build",
+            "--workspace",
+            "--target",
+            "wasm32-unknown-unknown",
+            "--release",
+        ])
+        .env("RUSTFLAGS", "-C link-arg=-s")
+        .current_dir(root)
+        .status()
+        .expect("Failed to run cargo");
+    assert!(status.success(), "Failed to build the contracts");
+    std::fs::read(root.join(format!(
+        "target/wasm32-unknown-unknown/release/{}.wasm",
+        name
+    )))
+    .expect("Missing contract wasm")
+}
+
+/// The fields of a protocol `Loan` checked here.
+#[derive(Deserialize)]
+struct Loan {
+    collateral: u128,
+    borrowed: u128,
+    token_collateral: HashMap<String, u128>,
+}
+
+struct Env {
+    worker: Worker<Sandbox>,
+    protocol: Contract,
+    oracle: Contract,
+    gusdt: Contract,
+    wbtc: Contract,
+    alice: Account,
+    bob: Account,
+}
+
+async fn deploy(root: &Account, name: &str, wasm: &[u8]) -> anyhow::Result<Contract> {
+    let account = root
+        .create_subaccount(name)
+        .initial_balance(NearToken::from_near(20))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(account.deploy(wasm).await?.into_result()?)
+}
+
+/// Reports the price of an asset from the oracle account, its only reporter.
+async fn report_price(
+    oracle: &Contract,
+    asset_id: &str,
+    multiplier: u128,
+    decimals: u8,
+) -> anyhow::Result<()> {
+    oracle
+        .call("report_prices")
+        .args_json(json!({
+            "prices": [{
+                "asset_id": asset_id,
+                "price": { "multiplier": multiplier.to_string(), "decimals": decimals },
+            }]
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn report_near_price(oracle: &Contract, multiplier: u128) -> anyhow::Result<()> {
+    report_price(oracle, "wrap.testnet", multiplier, 6).await
+}
+
+/// Reports the NEAR price from the oracle account as if it was pushed at `timestamp`.
+async fn report_near_price_at(
+    oracle: &Contract,
+    multiplier: u128,
+    timestamp: u64,
+) -> anyhow::Result<()> {
+    oracle
+        .call("report_prices_at")
+        .args_json(json!({
+            "prices": [{
+                "asset_id": "wrap.testnet",
+                "price": { "multiplier": multiplier.to_string(), "decimals": 6 },
+            }],
+            "timestamp": timestamp.to_string(),
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Registers `account` with the token, or with GUSD for the protocol.
+async fn storage_deposit(account: &Account, token: &Contract) -> anyhow::Result<()> {
+    account
+        .call(token.id(), "storage_deposit")
+        .args_json(json!({}))
+        .deposit(NearToken::from_millinear(10))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Registers `account_id` with the token and mints `amount` to it.
+async fn mint(
+    token: &Contract,
+    account_id: &near_workspaces::AccountId,
+    amount: u128,
+) -> anyhow::Result<()> {
+    token
+        .call("storage_deposit")
+        .args_json(json!({ "account_id": account_id }))
+        .deposit(NearToken::from_millinear(10))
+        .transact()
+        .await?
+        .into_result()?;
+    token
+        .call("ft_mint")
+        .args_json(json!({ "account_id": account_id, "amount": amount.to_string() }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+async fn get_loan(protocol: &Contract, account: &Account) -> anyhow::Result<Loan> {
+    let mut loans: HashMap<String, Loan> = protocol.view("get_all_loans").await?.json()?;
+    Ok(loans
+        .remove(account.id().as_str())
+        .expect("The account has no loan"))
+}
+
+async fn ft_balance_of(token: &Contract, account_id: &near_workspaces::AccountId) -> u128 {
+    let balance: String = token
+        .view("ft_balance_of")
+        .args_json(json!({ "account_id": account_id }))
+        .await
+        .unwrap()
+        .json()
+        .unwrap();
+    balance.parse().unwrap()
+}
+
+/// Deploys the protocol with its gUSDT token, the oracle and a mock WBTC token and points the
+/// protocol at the oracle.
+async fn setup() -> anyhow::Result<Env> {
+    let worker = near_workspaces::sandbox().await?;
+    let root = worker.root_account()?;
+
+    let oracle = deploy(&root, "oracle", &wasm("gratis_oracle")).await?;
+    oracle
+        .call("new")
+        .args_json(json!({
+            "allowed_accounts": [oracle.id()],
+            "recency_duration_sec": RECENCY_DURATION_SEC,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    // The oracle reports the price the protocol is initialized with, so that the update is not
+    // held back by the circuit breaker.
+    report_near_price(&oracle, 15000).await?;
+
+    let protocol = deploy(&root, "gratis", &wasm("gratis_protocol")).await?;
+    protocol
+        .call("new")
+        .args_json(json!({ "lower_collateral_accounts": [] }))
+        .transact()
+        .await?
+        .into_result()?;
+    protocol
+        .call("set_oracles")
+        .args_json(json!({ "oracle_ids": [oracle.id()] }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    // gUSDT lives on the `gusdt` sub-account of the protocol, which mints and burns it.
+    let gusdt = protocol
+        .as_account()
+        .create_subaccount("gusdt")
+        .initial_balance(NearToken::from_near(5))
+        .transact()
+        .await?
+        .into_result()?
+        .deploy(&wasm("gratis_gusdt"))
+        .await?
+        .into_result()?;
+    gusdt
+        .call("new")
+        .args_json(json!({ "owner_id": protocol.id() }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let wbtc = deploy(&root, "wbtc", &wasm("mock_ft")).await?;
+    wbtc.call("new")
+        .args_json(json!({
+            "owner_id": wbtc.id(),
+            "name": "Wrapped BTC",
+            "symbol": "WBTC",
+            "decimals": 8,
+        }))
+        .transact()
+        .await?
+        .into_result()?;
+    wbtc.call("storage_deposit")
+        .args_json(json!({ "account_id": protocol.id() }))
+        .deposit(NearToken::from_millinear(10))
+        .transact()
+        .await?
+        .into_result()?;
+
+    let alice = root
+        .create_subaccount("alice")
+        .initial_balance(NearToken::from_near(50))
+        .transact()
+        .await?
+        .into_result()?;
+    let bob = root
+        .create_subaccount("bob")
+        .initial_balance(NearToken::from_near(50))
+        .transact()
+        .await?
+        .into_result()?;
+
+    Ok(Env {
+        worker,
+        protocol,
+        oracle,
+        gusdt,
+        wbtc,
+        alice,
+        bob,
+    })
+}
+
+/// WBTC price, 0.0003 USDT, or 300 GUSD units, for the smallest unit of a token with 8
+/// decimals.
+const WBTC_PRICE: u128 = 300;
+
+/// Lists the mock WBTC as collateral with the given collateral factor and liquidation
+/// threshold, and has the protocol fetch its price.
+async fn set_wbtc_collateral(
+    env: &Env,
+    collateral_factor: &str,
+    liquidation_threshold: &str,
+) -> anyhow::Result<()> {
+    let mut config: Value = env.protocol.view("get_config").await?.json()?;
+    let wbtc_id = env.wbtc.id().to_string();
+    let assets = config["collateral_assets"].as_array_mut().unwrap();
+    if !assets.contains(&wbtc_id.clone().into()) {
+        assets.push(wbtc_id.clone().into());
+    }
+    config["collateral_configs"][&wbtc_id] = json!({
+        "collateral_factor": collateral_factor,
+        "liquidation_threshold": liquidation_threshold,
+        "debt_ceiling": null,
+    });
+    env.protocol
+        .call("update_config")
+        .args_json(json!({ "config": config }))
+        .transact()
+        .await?
+        .into_result()?;
+
+    report_near_price(&env.oracle, 15000).await?;
+    report_price(&env.oracle, &wbtc_id, WBTC_PRICE, 0).await?;
+    env.protocol
+        .call("get_prices")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+/// Deposits `amount` of WBTC of `account` as collateral through `ft_transfer_call`.
+async fn deposit_wbtc(env: &Env, account: &Account, amount: u128) -> anyhow::Result<()> {
+    account
+        .call(env.wbtc.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": env.protocol.id(),
+            "amount": amount.to_string(),
+            "msg": "",
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires the NEAR sandbox"]
+async fn test_get_prices_from_oracle() -> anyhow::Result<()> {
+    let Env {
+        protocol, oracle, ..
+    } = setup().await?;
+
+    let data: Value = protocol
+        .call("get_prices")
+        .max_gas()
+        .transact()
+        .await?
+        .json()?;
+    assert_eq!(data["prices"][0]["price"]["multiplier"], "15000");
+    let sources: Vec<String> = protocol.view("get_price_sources").await?.json()?;
+    assert_eq!(sources, vec![oracle.id().to_string()]);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires the NEAR sandbox"]
+async fn test_stale_oracle_price_ignored() -> anyhow::Result<()> {
+    let Env {
+        worker,
+        protocol,
+        oracle,
+        ..
+    } = setup().await?;
+
+    // The report replaces the fresh one of setup and is already older than the recency
+    // duration, so the oracle has no NEAR price left.
+    let now = worker.view_block().await?.timestamp();
+    let stale = now - 2 * RECENCY_DURATION_SEC as u64 * 1_000_000_000;
+    report_near_price_at(&oracle, 15100, stale).await?;
+
+    let data: Value = protocol
+        .call("get_prices")
+        .max_gas()
+        .transact()
+        .await?
+        .json()?;
+    assert_eq!(data["prices"][0]["price"]["multiplier"], "15000");
+    let sources: Vec<String> = protocol.view("get_price_sources").await?.json()?;
+    assert!(sources.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires the NEAR sandbox"]
+async fn test_deposit_borrow_and_close() -> anyhow::Result<()> {
+    let Env {
+        protocol, alice, ..
+    } = setup().await?;
+
+    storage_deposit(&alice, &protocol).await?;
+    alice
+        .call(protocol.id(), "deposit_collateral")
+        .deposit(NearToken::from_near(10))
+        .transact()
+        .await?
+        .into_result()?;
+    alice
+        .call(protocol.id(), "borrow")
+        .args_json(json!({ "amount": 100 * ONE_GUSD }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // Borrowing mints GUSD.
+    assert_eq!(ft_balance_of(&protocol, alice.id()).await, 100 * ONE_GUSD);
+    let supply: String = protocol.view("ft_total_supply").await?.json()?;
+    assert_eq!(supply, (100 * ONE_GUSD).to_string());
+    let loan = get_loan(&protocol, &alice).await?;
+    assert_eq!(loan.borrowed, 100 * ONE_GUSD);
+    // The 0.5% deposit fee is kept by the protocol.
+    assert_eq!(
+        loan.collateral,
+        NearToken::from_millinear(9950).as_yoctonear()
+    );
+
+    // Closing the loan burns the whole debt and returns the collateral.
+    let balance_before = alice.view_account().await?.balance;
+    alice
+        .call(protocol.id(), "close_loan")
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    assert_eq!(ft_balance_of(&protocol, alice.id()).await, 0);
+    let supply: String = protocol.view("ft_total_supply").await?.json()?;
+    assert_eq!(supply, "0");
+    let loans: HashMap<String, Loan> = protocol.view("get_all_loans").await?.json()?;
+    assert!(loans.is_empty());
+    let balance_after = alice.view_account().await?.balance;
+    assert!(balance_after > balance_before.saturating_add(NearToken::from_near(9)));
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires the NEAR sandbox"]
+async fn test_mint_mock_token() -> anyhow::Result<()> {
+    let Env { wbtc, alice, .. } = setup().await?;
+
+    mint(&wbtc, alice.id(), 100_000_000).await?;
+    assert_eq!(ft_balance_of(&wbtc, alice.id()).await, 100_000_000);
+    let metadata: Value = wbtc.view("ft_metadata").await?.json()?;
+    assert_eq!(metadata["symbol"], "WBTC");
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires the NEAR sandbox"]
+async fn test_partial_repay() -> anyhow::Result<()> {
+    let Env {
+        protocol, alice, ..
+    } = setup().await?;
+
+    storage_deposit(&alice, &protocol).await?;
+    alice
+        .call(protocol.id(), "deposit_collateral")
+        .deposit(NearToken::from_near(10))
+        .transact()
+        .await?
+        .into_result()?;
+    alice
+        .call(protocol.id(), "borrow")
+        .args_json(json!({ "amount": 100 * ONE_GUSD }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    alice
+        .call(protocol.id(), "repay")
+        .args_json(json!({ "amount": 40 * ONE_GUSD }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // The repaid GUSD is burned and the loan keeps its collateral.
+    assert_eq!(ft_balance_of(&protocol, alice.id()).await, 60 * ONE_GUSD);
+    let supply: String = protocol.view("ft_total_supply").await?.json()?;
+    assert_eq!(supply, (60 * ONE_GUSD).to_string());
+    let loan = get_loan(&protocol, &alice).await?;
+    assert_eq!(loan.borrowed, 60 * ONE_GUSD);
+    assert_eq!(
+        loan.collateral,
+        NearToken::from_millinear(9950).as_yoctonear()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires the NEAR sandbox"]
+async fn test_token_collateral_through_ft_transfer_call() -> anyhow::Result<()> {
+    let env = setup().await?;
+    let Env {
+        protocol,
+        wbtc,
+        alice,
+        ..
+    } = &env;
+    set_wbtc_collateral(&env, "0.7", "0.8").await?;
+    mint(wbtc, alice.id(), 1_000_000).await?;
+
+    deposit_wbtc(&env, alice, 1_000_000).await?;
+    assert_eq!(ft_balance_of(wbtc, alice.id()).await, 0);
+    assert_eq!(ft_balance_of(wbtc, protocol.id()).await, 1_000_000);
+    let loan = get_loan(protocol, alice).await?;
+    assert_eq!(loan.token_collateral[wbtc.id().as_str()], 1_000_000);
+
+    // 0.01 WBTC is worth 300 GUSD, 210 GUSD of borrowing power.
+    storage_deposit(alice, protocol).await?;
+    alice
+        .call(protocol.id(), "borrow")
+        .args_json(json!({ "amount": 200 * ONE_GUSD }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    assert_eq!(ft_balance_of(protocol, alice.id()).await, 200 * ONE_GUSD);
+
+    // Withdrawing collateral the debt still needs fails, the rest is sent back.
+    let result = alice
+        .call(protocol.id(), "remove_token_collateral")
+        .args_json(json!({ "asset_id": wbtc.id(), "amount": "100000" }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_failure());
+    alice
+        .call(protocol.id(), "repay")
+        .args_json(json!({ "amount": 60 * ONE_GUSD }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    alice
+        .call(protocol.id(), "remove_token_collateral")
+        .args_json(json!({ "asset_id": wbtc.id(), "amount": "100000" }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    assert_eq!(ft_balance_of(wbtc, alice.id()).await, 100_000);
+    assert_eq!(ft_balance_of(wbtc, protocol.id()).await, 900_000);
+    let loan = get_loan(protocol, alice).await?;
+    assert_eq!(loan.token_collateral[wbtc.id().as_str()], 900_000);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires the NEAR sandbox"]
+async fn test_liquidate_token_collateral() -> anyhow::Result<()> {
+    let env = setup().await?;
+    let Env {
+        protocol,
+        wbtc,
+        alice,
+        bob,
+        ..
+    } = &env;
+    set_wbtc_collateral(&env, "0.7", "0.8").await?;
+    // Bob borrows the GUSD to liquidate with against NEAR.
+    storage_deposit(bob, protocol).await?;
+    storage_deposit(bob, wbtc).await?;
+    bob.call(protocol.id(), "deposit_collateral")
+        .deposit(NearToken::from_near(10))
+        .transact()
+        .await?
+        .into_result()?;
+    bob.call(protocol.id(), "borrow")
+        .args_json(json!({ "amount": 100 * ONE_GUSD }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    mint(wbtc, alice.id(), 1_000_000).await?;
+    deposit_wbtc(&env, alice, 1_000_000).await?;
+    storage_deposit(alice, protocol).await?;
+    alice
+        .call(protocol.id(), "borrow")
+        .args_json(json!({ "amount": 200 * ONE_GUSD }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    let result = bob
+        .call(protocol.id(), "liquidate_with_repay")
+        .args_json(json!({
+            "account_id": alice.id(),
+            "collateral_asset": wbtc.id(),
+            "amount": (50 * ONE_GUSD).to_string(),
+        }))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(result.is_failure());
+
+    // Lowering the liquidation threshold to 60% leaves the 200 GUSD of debt above the 180 GUSD
+    // liquidation limit of the loan.
+    set_wbtc_collateral(&env, "0.5", "0.6").await?;
+    let repaid: String = bob
+        .call(protocol.id(), "liquidate_with_repay")
+        .args_json(json!({
+            "account_id": alice.id(),
+            "collateral_asset": wbtc.id(),
+            "amount": (50 * ONE_GUSD).to_string(),
+        }))
+        .max_gas()
+        .transact()
+        .await?
+        .json()?;
+    assert_eq!(repaid, (50 * ONE_GUSD).to_string());
+
+    // The 50 GUSD repaid by bob are burned for the WBTC worth them plus the 5% liquidation
+    // bonus.
+    let seized = 50 * ONE_GUSD * 105 / 100 / WBTC_PRICE;
+    assert_eq!(ft_balance_of(protocol, bob.id()).await, 50 * ONE_GUSD);
+    assert_eq!(ft_balance_of(wbtc, bob.id()).await, seized);
+    assert_eq!(ft_balance_of(wbtc, protocol.id()).await, 1_000_000 - seized);
+    let loan = get_loan(protocol, alice).await?;
+    assert_eq!(loan.borrowed, 150 * ONE_GUSD);
+    assert_eq!(
+        loan.token_collateral[wbtc.id().as_str()],
+        1_000_000 - seized
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires the NEAR sandbox"]
+async fn test_reserve_supply_and_redeem() -> anyhow::Result<()> {
+    let Env {
+        protocol,
+        gusdt,
+        alice,
+        bob,
+        ..
+    } = setup().await?;
+
+    storage_deposit(&alice, &protocol).await?;
+    alice
+        .call(protocol.id(), "deposit_collateral")
+        .deposit(NearToken::from_near(10))
+        .transact()
+        .await?
+        .into_result()?;
+    alice
+        .call(protocol.id(), "borrow")
+        .args_json(json!({ "amount": 100 * ONE_GUSD }))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+
+    // Supplying mints gUSDT, one per GUSD before any fee accrued.
+    storage_deposit(&alice, &gusdt).await?;
+    let shares: String = alice
+        .call(protocol.id(), "reserve_supply")
+        .args_json(json!({ "amount": (50 * ONE_GUSD).to_string() }))
+        .max_gas()
+        .transact()
+        .await?
+        .json()?;
+    assert_eq!(shares, (50 * ONE_GUSD).to_string());
+    assert_eq!(ft_balance_of(&gusdt, alice.id()).await, 50 * ONE_GUSD);
+    assert_eq!(ft_balance_of(&protocol, alice.id()).await, 50 * ONE_GUSD);
+    let metadata: Value = gusdt.view("ft_metadata").await?.json()?;
+    assert_eq!(metadata["symbol"], "gUSDT");
+
+    // So does transferring GUSD to the protocol with the `supply` message.
+    alice
+        .call(protocol.id(), "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": protocol.id(),
+            "amount": (10 * ONE_GUSD).to_string(),
+            "msg": "supply",
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    assert_eq!(ft_balance_of(&gusdt, alice.id()).await, 60 * ONE_GUSD);
+    assert_eq!(ft_balance_of(&protocol, alice.id()).await, 40 * ONE_GUSD);
+
+    // gUSDT moves with the standard transfer, and its holder redeems it.
+    storage_deposit(&bob, &gusdt).await?;
+    alice
+        .call(gusdt.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": bob.id(), "amount": (20 * ONE_GUSD).to_string() }))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+    storage_deposit(&bob, &protocol).await?;
+    let amount: String = bob
+        .call(protocol.id(), "reserve_redeem")
+        .args_json(json!({ "shares": (20 * ONE_GUSD).to_string() }))
+        .max_gas()
+        .transact()
+        .await?
+        .json()?;
+    assert_eq!(amount, (20 * ONE_GUSD).to_string());
+    assert_eq!(ft_balance_of(&gusdt, bob.id()).await, 0);
+    assert_eq!(ft_balance_of(&protocol, bob.id()).await, 20 * ONE_GUSD);
+    let reserve: Value = protocol.view("get_reserve").await?.json()?;
+    assert_eq!(reserve["total_shares"], (40 * ONE_GUSD).to_string());
+    assert_eq!(reserve["token_id"], gusdt.id().as_str());
+    Ok(())
+}